@@ -0,0 +1,132 @@
+// Compares SubEditor, with every line a gap buffer or with only the lines around the cursor and
+// the rest in a rope, with the piece table and the rope themselves on a generated file of a few
+// megabytes: how long opening it takes, how much memory it takes up and how long edits take.
+// `cargo bench -- 32` runs it on 32 MB instead of 8.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use med::editor::subeditor::{Backing, Buffer, SubEditor};
+use med::editor::subeditor::format::Format;
+use med::editor::subeditor::piece::PieceTable;
+use med::editor::subeditor::rope::Rope;
+
+// Keeps count of the bytes allocated, to tell how much memory a buffer takes
+struct Counting;
+
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        IN_USE.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, size: usize) -> *mut u8 {
+        IN_USE.fetch_add(size, Ordering::Relaxed);
+        IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, size)
+    }
+}
+
+#[global_allocator]
+static ALLOC: Counting = Counting;
+
+static TYPED: usize = 10_000; // chars typed in one place
+static EDITS: usize = 2_000; // chars inserted and removed again all over the file
+static READS: usize = 2_000; // lines read, as drawing the screen does
+
+// Same numbers on every run, so that runs can be compared
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self, below: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % below as u64) as usize
+    }
+}
+
+// Lines of made up words, like source code or a log
+fn generate(size: usize) -> Vec<u8> {
+    let words = ["let", "fn", "editor", "line", "buffer", "=", "{", "}", "0x1f", "ünïcode", "->", "self"];
+    let mut random = Random(42);
+    let mut text = String::with_capacity(size + 100);
+    while text.len() < size {
+        let indent = random.next(4) * 4;
+        text.push_str(&" ".repeat(indent));
+        for _ in 0..3 + random.next(10) {
+            text.push_str(words[random.next(words.len())]);
+            text.push(' ');
+        }
+        text.push('\n');
+    }
+    text.pop();
+    text.into_bytes()
+}
+
+fn per(time: Duration, count: usize) -> Duration {
+    time / count as u32
+}
+
+fn measure<B: Buffer>(name: &str, bytes: &[u8], open: fn(&[u8]) -> B) {
+    let in_use = IN_USE.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut buffer = open(bytes);
+    let open_time = start.elapsed();
+    let memory = IN_USE.load(Ordering::Relaxed).saturating_sub(in_use);
+
+    let lines = buffer.num_lines();
+    let mut random = Random(7);
+
+    let (line, mut col) = (lines / 2, 0);
+    let start = Instant::now();
+    for _ in 0..TYPED {
+        buffer.insert_at((line, col), "x");
+        col += 1;
+    }
+    let typing = per(start.elapsed(), TYPED);
+
+    let start = Instant::now();
+    for _ in 0..EDITS {
+        let pos = (random.next(lines), random.next(3));
+        buffer.insert_at(pos, "y");
+        buffer.remove_range(pos, (pos.0, pos.1 + 1));
+    }
+    let edit = per(start.elapsed(), EDITS * 2);
+
+    let start = Instant::now();
+    let mut read = 0;
+    for _ in 0..READS {
+        read += buffer.line(random.next(lines)).map_or(0, |line| line.len());
+    }
+    let reading = per(start.elapsed(), READS);
+
+    println!(
+        "{:<12} {:>10.2?} {:>9.1} MB {:>12.2?} {:>12.2?} {:>12.2?}   ({} lines, {} bytes read)",
+        name, open_time, memory as f64 / 1_048_576.0, typing, edit, reading, lines, read
+    );
+}
+
+fn main() {
+    // cargo passes --bench, anything else that is a number is the size in MB
+    let mb = env::args().skip(1).find_map(|arg| arg.parse::<usize>().ok()).unwrap_or(8);
+    let bytes = generate(mb << 20);
+
+    println!("{} MB file", mb);
+    println!("{:<12} {:>10} {:>12} {:>12} {:>12} {:>12}", "", "open", "memory", "typing", "random edit", "line");
+    measure("gap buffers", &bytes, |bytes| {
+        let (format, lines) = Format::split(bytes);
+        SubEditor::with_lines(lines.into_iter(), format)
+    });
+    measure("gap + rope", &bytes, |bytes| SubEditor::with_bytes(bytes, Backing::Rope));
+    measure("gap + piece", &bytes, |bytes| SubEditor::with_bytes(bytes, Backing::PieceTable));
+    measure("piece table", &bytes, |bytes| PieceTable::new(String::from_utf8_lossy(bytes).into_owned()));
+    measure("rope", &bytes, |bytes| Rope::new(&String::from_utf8_lossy(bytes)));
+}
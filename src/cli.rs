@@ -0,0 +1,181 @@
+use std::fmt;
+
+pub static STDIN: &str = "-";
+
+pub static USAGE: &str = "\
+Usage: med [OPTIONS] [+LINE] <FILE>...
+
+Opens each FILE in turn, <Ctrl> + Q moves on to the next one.
+Use - as FILE to read the buffer from stdin.
+
+Options:
+  +LINE          Place the cursor on LINE of the file that follows
+  -r, --readonly Open the files without allowing edits
+  -b, --backup   Keep the previous version of a saved file as FILE~
+  -t, --tab-width N
+                 Show tabs N columns wide and indent by N columns (default 4)
+  -s, --soft-tabs
+                 Indent with spaces instead of tabs
+  -p, --piece-table
+                 Keep the lines of files of 1 MB and more in a piece table
+                 instead of a rope
+  -h, --help     Print this help and exit
+  -V, --version  Print the version and exit
+      --         Treat every following argument as a file";
+
+#[derive(Debug, PartialEq)]
+pub struct Target {
+    pub path: String,
+    pub line: Option<usize>
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub files: Vec<Target>,
+    pub readonly: bool,
+    pub backup: bool,
+    pub tab_width: usize,
+    pub soft_tabs: bool,
+    pub piece_table: bool
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Cli {
+    Help,
+    Version,
+    Edit(Options)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CliError {
+    UnknownOption(String),
+    BadLine(String),
+    BadTabWidth(String),
+    MissingValue(String),
+    DanglingLine,
+    MultipleStdin,
+    NoFiles
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::UnknownOption(opt) => write!(f, "unknown option '{}'", opt),
+            CliError::BadLine(arg) => write!(f, "invalid line number '{}'", arg),
+            CliError::BadTabWidth(arg) => write!(f, "invalid tab width '{}'", arg),
+            CliError::MissingValue(opt) => write!(f, "'{}' needs a value", opt),
+            CliError::DanglingLine => write!(f, "+LINE must be followed by a file"),
+            CliError::MultipleStdin => write!(f, "stdin (-) can only be opened once"),
+            CliError::NoFiles => write!(f, "no file given")
+        }
+    }
+}
+
+fn parse_line(arg: &str) -> Result<usize, CliError> {
+    match arg[1..].parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(CliError::BadLine(arg.to_string()))
+    }
+}
+
+// Parses the arguments following the program name
+pub fn parse<I>(args: I) -> Result<Cli, CliError>
+where I: IntoIterator<Item = String>, {
+    let mut opts = Options { files: Vec::new(), readonly: false, backup: false, tab_width: 4, soft_tabs: false, piece_table: false };
+    let mut line = None;
+    let mut only_files = false;
+    let mut seen_stdin = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !only_files && arg.len() > 1 && arg.starts_with('-') {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Cli::Help),
+                "-V" | "--version" => return Ok(Cli::Version),
+                "-r" | "--readonly" => opts.readonly = true,
+                "-b" | "--backup" => opts.backup = true,
+                "-s" | "--soft-tabs" => opts.soft_tabs = true,
+                "-p" | "--piece-table" => opts.piece_table = true,
+                "-t" | "--tab-width" => {
+                    let width = args.next().ok_or(CliError::MissingValue(arg))?;
+                    opts.tab_width = match width.parse::<usize>() {
+                        Ok(n) if n > 0 => n,
+                        _ => return Err(CliError::BadTabWidth(width))
+                    };
+                }
+                "--" => only_files = true,
+                _ => return Err(CliError::UnknownOption(arg))
+            }
+        } else if !only_files && arg.len() > 1 && arg.starts_with('+') {
+            line = Some(parse_line(&arg)?);
+        } else {
+            if arg == STDIN {
+                if seen_stdin { return Err(CliError::MultipleStdin); }
+                seen_stdin = true;
+            }
+            opts.files.push(Target { path: arg, line: line.take() });
+        }
+    }
+
+    if line.is_some() { return Err(CliError::DanglingLine); }
+    if opts.files.is_empty() { return Err(CliError::NoFiles); }
+
+    Ok(Cli::Edit(opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Cli, CliError> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn files(args: &[&str]) -> Vec<Target> {
+        match self::args(args) {
+            Ok(Cli::Edit(opts)) => opts.files,
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn lines_go_with_the_next_file() {
+        assert_eq!(files(&["+3", "a", "b"]), vec![
+            Target { path: "a".to_string(), line: Some(3) },
+            Target { path: "b".to_string(), line: None }
+        ]);
+        assert_eq!(args(&["+0", "a"]), Err(CliError::BadLine("+0".to_string())));
+        assert_eq!(args(&["+x", "a"]), Err(CliError::BadLine("+x".to_string())));
+        assert_eq!(args(&["a", "+3"]), Err(CliError::DanglingLine));
+    }
+
+    #[test]
+    fn everything_after_double_dash_is_a_file() {
+        assert_eq!(files(&["--", "-r", "+3"]), vec![
+            Target { path: "-r".to_string(), line: None },
+            Target { path: "+3".to_string(), line: None }
+        ]);
+    }
+
+    #[test]
+    fn stdin_only_once() {
+        assert_eq!(files(&["-"]), vec![Target { path: STDIN.to_string(), line: None }]);
+        assert_eq!(args(&["-", "a", "-"]), Err(CliError::MultipleStdin));
+    }
+
+    #[test]
+    fn options() {
+        match args(&["-r", "--backup", "-t", "2", "-s", "--piece-table", "a"]) {
+            Ok(Cli::Edit(opts)) => {
+                assert!(opts.readonly && opts.backup && opts.soft_tabs && opts.piece_table);
+                assert_eq!(opts.tab_width, 2);
+            }
+            other => panic!("{:?}", other)
+        }
+        assert_eq!(args(&["a", "-h"]), Ok(Cli::Help));
+        assert_eq!(args(&["-t", "0", "a"]), Err(CliError::BadTabWidth("0".to_string())));
+        assert_eq!(args(&["a", "-t"]), Err(CliError::MissingValue("-t".to_string())));
+        assert_eq!(args(&["-x"]), Err(CliError::UnknownOption("-x".to_string())));
+        assert_eq!(args(&[]), Err(CliError::NoFiles));
+    }
+}
@@ -1,55 +1,378 @@
+use std::env;
+use std::fs;
 use std::io::{self, Write};
-use std::time::Duration;
-use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
 use crossterm::{
     cursor,
-    event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers },
+    event::{self, poll, read, Event, KeyCode, KeyEvent, KeyModifiers },
     style,
     terminal,
     ExecutableCommand, Result
 };
 
+use crate::cli::STDIN;
+use subeditor::search::{Matcher, Query};
+use subeditor::{swap, Backing, Opened, Replacing, SubEditor};
+use subeditor::format::{Encoding, Format};
+use layout::Cell;
+
 pub mod subeditor;
+mod clipboard;
+mod diff;
+mod hex;
+mod layout;
+pub mod indent;
 
 pub struct Editor<'a> {
     pub term: &'a mut dyn Write,
     pub subed: subeditor::SubEditor,
-    pub fname: &'a str
+    pub fname: String,
+    pub backup: bool, // keep the previous version of the file as `fname~` when saving
+    pub indent: indent::Indent,
+    top: usize, // first buffer line shown in the viewport
+    left: usize, // first column shown when lines are not wrapped
+    wrap: bool,
+    status: String, // message shown on the last row until the next key
+    clipboard: clipboard::Clipboard,
+    search: Query, // last search
+    matcher: Option<Matcher>, // the last search compiled, None if it is empty or invalid
+    searching: bool, // if its matches are highlighted
+    swap_changes: u64, // the changes to the buffer that the swap file is up to date with
+    swap_time: Option<Instant>, // when the swap file was last written, None if it was not
+    dir: bool, // if the buffer lists the entries of the directory `fname`
+    locked: bool, // a view (eg. a directory listing or hex dump) that cannot be made editable
+    binary: Option<Vec<u8>>, // the bytes of a file that does not look like text, until asked how to show it
+    pub next: Option<String> // picked from a directory listing, to be opened after quitting this one
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Highlight {
+    Selection,
+    Match
+}
+
+// What has to be drawn again after handling an event
+#[derive(PartialEq)]
+enum Redraw {
+    Nothing,
+    Line,
+    All
 }
 
 static FNAME_WIDTH: usize = 20; // even and more than 3
 static ROW_OFFSET: usize = 2;
 static COL_OFFSET: usize = 4 ; // even
+static WRAP_MARKER: char = '↪';
+static MODIFIED: &str = " [+]"; // after the file name when there are unsaved changes
+static READ_ONLY: &str = " [RO]";
+static SWAP_INTERVAL: Duration = Duration::from_secs(10); // while typing without a pause
+static INDEX_INTERVAL: Duration = Duration::from_millis(250); // between updates of the indexing progress
+
+enum Travel {
+    Steps(isize),
+    Secs(i64)
+}
+
+// Parses a history offset such as -3 (states), +30s, -5m, -2h or -1d, without a sign it goes back
+fn parse_travel(spec: &str) -> Option<Travel> {
+    let spec = spec.trim();
+    let (sign, spec) = match spec.chars().next()? {
+        '+' => (1, &spec[1..]),
+        '-' => (-1, &spec[1..]),
+        _ => (-1, spec)
+    };
+    let unit = match spec.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return spec.parse::<isize>().ok().map(|n| Travel::Steps(sign * n))
+    };
+    spec[..spec.len()-1].parse::<i64>().ok().map(|n| Travel::Secs(sign as i64 * n * unit))
+}
+
+fn fmt_ago(secs: u64) -> String {
+    match secs {
+        0..=9 => "just now".to_string(),
+        10..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400)
+    }
+}
+
+// Keys that change the buffer, which are refused when it is read only
+fn edits(key: &KeyEvent) -> bool {
+    match key.code {
+        KeyCode::Enter | KeyCode::Backspace | KeyCode::Delete | KeyCode::Tab => true,
+        KeyCode::Left | KeyCode::Right => key.modifiers == KeyModifiers::ALT,
+        KeyCode::Char(ch) if key.modifiers == KeyModifiers::CONTROL => "xvzyrXVZYR".contains(ch),
+        KeyCode::Char(ch) if key.modifiers == KeyModifiers::ALT => "eftEFT".contains(ch),
+        KeyCode::Char(_) => !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT),
+        _ => false
+    }
+}
+
+// The entries of a directory as the lines of a listing, subdirectories first and ending in a slash
+fn list_dir(path: &Path) -> io::Result<Vec<String>> {
+    let (mut dirs, mut files) = (Vec::new(), Vec::new());
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.path().is_dir() { dirs.push(name + "/"); } else { files.push(name); }
+    }
+    dirs.sort();
+    files.sort();
+    Ok(std::iter::once("../".to_string()).chain(dirs).chain(files).collect())
+}
+
+// Puts the terminal back the way it was before med started, whatever state med left it in
+pub fn restore_terminal() {
+    let mut out = io::stdout();
+    out.execute(event::DisableBracketedPaste).ok();
+    out.execute(terminal::LeaveAlternateScreen).ok();
+    terminal::disable_raw_mode().ok();
+}
+
+// Restores the terminal when dropped, so that it happens however med stops
+pub struct TerminalGuard;
 
-impl Editor<'_> {
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+impl<'a> Editor<'a> {
+
+    pub fn new(term: &'a mut dyn Write, mut subed: subeditor::SubEditor, fname: &str, readonly: bool) -> Editor<'a> {
+        if readonly { subed.set_readonly(true); }
+        Editor {
+            term,
+            subed,
+            fname: fname.to_string(),
+            backup: false,
+            indent: indent::Indent::default(),
+            top: 0,
+            left: 0,
+            wrap: true,
+            status: String::new(),
+            clipboard: clipboard::Clipboard::from_env(),
+            search: Query::default(),
+            matcher: None,
+            searching: false,
+            swap_changes: 0,
+            swap_time: None,
+            dir: false,
+            locked: false,
+            binary: None,
+            next: None
+        }
+    }
+
+    // Opens the file at `path`. When there is no text file there to edit, the buffer starts out
+    // with what stands for it instead and the status line tells what was found.
+    pub fn open(term: &'a mut dyn Write, path: &str, readonly: bool, backing: Backing) -> Result<Editor<'a>> {
+        let mut ed = Editor::new(term, SubEditor::with_lines(std::iter::empty(), Format::default()), path, readonly);
+        match SubEditor::open(path, backing)? {
+            Opened::File(subed) => {
+                if subed.is_readonly() && !readonly {
+                    ed.set_status(&format!("\"{}\" is not writable, opened read only (Alt + O makes it editable)", path));
+                } else if subed.is_mapped() {
+                    ed.set_status(&format!("\"{}\" is huge, its lines are read from the file as they are needed", path));
+                }
+                ed.subed = subed;
+            }
+            Opened::New => {
+                let parent = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty());
+                match parent {
+                    Some(dir) if !dir.is_dir() => ed.set_status(&format!("\"{}\" is a new file, but {} does not exist", path, dir.display())),
+                    _ => ed.set_status(&format!("\"{}\" is a new file, saving creates it", path))
+                }
+            }
+            Opened::Denied => {
+                // the empty buffer stands for a file with text in it, saving it would wipe that out
+                ed.subed.set_readonly(true);
+                ed.locked = true;
+                ed.set_status(&format!("Permission denied, \"{}\" cannot be read", path));
+            }
+            Opened::Directory => {
+                ed.dir = true;
+                ed.locked = true;
+                match list_dir(Path::new(path)) {
+                    Ok(entries) => {
+                        ed.subed = SubEditor::with_lines(entries.into_iter(), Format::default());
+                        ed.subed.set_readonly(true);
+                        ed.set_status(&format!("\"{}\" is a directory, Enter opens the entry under the cursor", path));
+                    }
+                    Err(e) => ed.set_status(&format!("\"{}\" is a directory that cannot be read ({})", path, e))
+                }
+            }
+            Opened::TooLarge(kind) => {
+                // like an unreadable file, the empty buffer must never be saved over it
+                ed.subed.set_readonly(true);
+                ed.locked = true;
+                ed.set_status(&format!("\"{}\" is a huge {} file, only huge UTF-8 text files can be opened", path, kind));
+            }
+            Opened::Binary(bytes) => {
+                ed.subed = SubEditor::with_bytes(&bytes, backing);
+                ed.binary = Some(bytes);
+            }
+        }
+        if readonly { ed.subed.set_readonly(true); }
+        Ok(ed)
+    }
 
     pub fn init(&mut self) -> Result<()> {
         self.term.execute(terminal::EnterAlternateScreen)?;
-        terminal::enable_raw_mode()?;    
+        self.term.execute(event::EnableBracketedPaste)?;
+        terminal::enable_raw_mode()?;
 
         Ok(())
     }
 
-    pub fn exit(&mut self) -> Result<()> {
-        self.term.execute(terminal::LeaveAlternateScreen)?;
-        terminal::disable_raw_mode()?;    
-
-        Ok(())
+    // Saves the buffer somewhere after med failed: to the swap file, so that opening the file
+    // again offers to recover it, or for stdin buffers to a file in the temp directory
+    pub fn dump(&self) -> io::Result<PathBuf> {
+        if let Some(path) = self.swap_path() {
+            if self.subed.write_swap(&path, Path::new(&self.fname)).is_ok() { return Ok(path); }
+        }
+        let path = env::temp_dir().join(format!("med-recover-{}.txt", process::id()));
+        fs::write(&path, self.subed.lines().join("\n") + "\n")?;
+        Ok(path)
     }
 
     pub fn disp_name(&self) -> Result<String> {
-        let filename =  Path::new(self.fname).file_name()
-                        .ok_or(io::Error::new(io::ErrorKind::PermissionDenied, "Could not get file name"))?
-                        .to_str()
-                        .ok_or(io::Error::new(io::ErrorKind::PermissionDenied, "Could not get file name"))?;
-        let fnamelen = filename.len();
-        let start = fnamelen + 2 - FNAME_WIDTH/2;
+        if self.fname == STDIN { return Ok("[stdin]".to_string()); }
+        // directories like `.` or `/` have no name of their own
+        let filename = Path::new(&self.fname).file_name().and_then(|name| name.to_str()).unwrap_or(&self.fname);
+        if layout::width(filename) + 1 < FNAME_WIDTH {
+            Ok(filename.to_string())
+        } else {
+            Ok(layout::shorten(filename, FNAME_WIDTH/2, FNAME_WIDTH/2 - 2))
+        }
+    }
+
+    // Number of terminal rows available for the buffer, the last one is the status line
+    pub fn text_height(&self) -> Result<usize> {
+        let (_, rows) = terminal::size()?;
+        Ok((rows as usize).saturating_sub(ROW_OFFSET + 1).max(1))
+    }
 
-        if fnamelen + 1 < FNAME_WIDTH { 
-            Ok(format!("{}", filename) )
-        } else { 
-            Ok(format!( "{}...{}", &filename[0..FNAME_WIDTH/2], &filename[start..fnamelen])) 
+    // Width of the line number column, grows with the number of lines
+    pub fn gutter_width(&self) -> usize {
+        let digits = self.subed.num_lines().to_string().len();
+        COL_OFFSET.max(digits + 1)
+    }
+
+    // Number of terminal columns available for the text of a line
+    pub fn text_width(&self) -> Result<usize> {
+        let (cols, _) = terminal::size()?;
+        Ok((cols as usize).saturating_sub(self.gutter_width()).max(1))
+    }
+
+    // How line n is laid out on the screen, nothing past the last line
+    fn line_cells(&self, n: usize) -> Vec<Cell> {
+        self.subed.line_text(n).map_or(Vec::new(), |line| layout::cells(&line, self.indent.width))
+    }
+
+    // Number of screen rows line n takes up, there is always room for the cursor after the last char
+    fn line_rows(&self, n: usize, width: usize) -> usize {
+        if !self.wrap { return 1; }
+        layout::rows(&self.line_cells(n), width).len()
+    }
+
+    // Screen column of the cursor, counted from the start of its line
+    fn cursor_x(&self) -> usize {
+        layout::x_of(&self.line_cells(self.subed.curr_line_num()), self.subed.cursor())
+    }
+
+    // Puts the cursor on what is shown at screen column `x` of the current line, or at its end
+    fn move_to_x(&mut self, x: usize) {
+        let line = self.subed.curr_line_num();
+        let col = layout::col_at(&self.line_cells(line), x);
+        self.subed.move_to((line, col));
+    }
+
+    // The text of the current line before the cursor
+    fn before_cursor(&self) -> String {
+        let line = self.subed.get_line(self.subed.curr_line_num()).map_or(String::new(), |line| line.show());
+        line.chars().take(self.subed.cursor()).collect()
+    }
+
+    // The column backspace goes back to when it removes a whole level of soft indentation
+    fn dedent_col(&self) -> Option<usize> {
+        self.indent.dedent(&self.before_cursor())
+    }
+
+    // Types a char, a closing bracket first on a line takes it back one indent level
+    fn insert_char(&mut self, ch: char) {
+        if let Some(col) = self.indent.close(&self.before_cursor(), ch) {
+            let line = self.subed.curr_line_num();
+            self.subed.remove_range((line, col), self.subed.pos());
+        }
+        self.subed.insert(ch);
+    }
+
+    // Enter keeps the indentation of the line, and adds a level after what opens a block
+    fn insert_newline(&mut self) {
+        let indent = self.indent.next_line(&self.before_cursor());
+        if indent.is_empty() {
+            self.subed.insert_newline();
+        } else {
+            self.subed.insert_str(&format!("\n{}", indent));
+        }
+    }
+
+    // Screen position of the cursor relative to the viewport, as (row, column)
+    fn cursor_pos(&self) -> Result<(usize, usize)> {
+        let line = self.subed.curr_line_num();
+        let cells = self.line_cells(line);
+        let x = layout::x_of(&cells, self.subed.cursor());
+        if self.wrap {
+            let width = self.text_width()?;
+            let rows: usize = (self.top..line).map(|n| self.line_rows(n, width)).sum();
+            let line_rows = layout::rows(&cells, width);
+            let row = layout::row_of(&line_rows, layout::cell_at(&cells, self.subed.cursor()));
+            Ok((rows + row, x - line_rows[row].1))
+        } else {
+            Ok((line - self.top, x - self.left))
+        }
+    }
+
+    // Moves the viewport so that the cursor is visible, returns true if it moved
+    pub fn scroll(&mut self) -> Result<bool> {
+        let (top, left) = (self.top, self.left);
+        let height = self.text_height()?;
+        let width = self.text_width()?;
+        let line = self.subed.curr_line_num();
+
+        if line < self.top { self.top = line; }
+        // every line takes up at least one row, so with wrapping this is only as far as it can be
+        if line >= self.top + height { self.top = line + 1 - height; }
+        if self.wrap {
+            self.left = 0;
+            while self.top < line && self.cursor_pos()?.0 >= height { self.top += 1; }
+        } else {
+            // the whole of a wide char under the cursor has to be visible
+            let cells = self.line_cells(line);
+            let x = layout::x_of(&cells, self.subed.cursor());
+            let cursor_width = cells.get(layout::cell_at(&cells, self.subed.cursor())).map_or(1, |cell| cell.width.max(1));
+            if x < self.left { self.left = x; }
+            if x + cursor_width > self.left + width { self.left = x + cursor_width - width; }
         }
+        // the lines of a huge file that can be on the screen
+        self.subed.load(self.top, self.top + height);
+
+        Ok(top != self.top || left != self.left)
+    }
+
+    // Switches between soft wrapping long lines and scrolling them horizontally
+    pub fn toggle_wrap(&mut self) -> Result<()> {
+        self.wrap = !self.wrap;
+        self.refresh()
     }
 
     pub fn show_header(&mut self) -> Result<()> {
@@ -57,202 +380,981 @@ impl Editor<'_> {
         self.term.execute(style::SetForegroundColor(style::Color::White))?;
 
         self.term.execute(cursor::MoveTo(0,0))?;
-        
+
         let (cols,_) = terminal::size()?;
-        let title_width: usize = cols as usize - FNAME_WIDTH - 3;
+        let name_width = FNAME_WIDTH + READ_ONLY.len() + MODIFIED.len();
+        let title_width: usize = (cols as usize).saturating_sub(name_width + 3);
+        let readonly = if self.subed.is_readonly() { READ_ONLY } else { "" };
+        let modified = if self.subed.is_modified() { MODIFIED } else { "" };
+        let title = match self.subed.indexing() {
+            Some(percent) => format!(" Med v0.1 - indexing lines {}% ", percent),
+            None => " Med v0.1 ".to_string()
+        };
 
         self.term.execute(cursor::MoveTo(0,0))?;
-        print!( "{:^twidth$} | {:^fwidth$}", 
-                " Med v0.1 ", self.disp_name()?,
-                twidth=title_width, fwidth=FNAME_WIDTH
-        );
+        // centered by screen columns, a name can have wide chars in it
+        print!("{:^twidth$} | {}", title, layout::center(&(self.disp_name()? + readonly + modified), name_width), twidth=title_width);
         self.term.execute(cursor::MoveToNextLine(1))?;
         print!("{}", vec!['¯'; cols as usize].iter().collect::<String>());
 
         self.term.execute(style::ResetColor)?;
-        self.term.execute(cursor::RestorePosition)?; 
-        
+        self.term.execute(cursor::RestorePosition)?;
+
         Ok(())
     }
 
-    pub fn show_content(&mut self) -> Result<()> {
-        self.term.execute(cursor::SavePosition)?;        
-        self.term.execute(cursor::MoveTo(0, ROW_OFFSET as u16))?;
-        for (i,line) in self.subed.get_lines().iter().enumerate() {
+    // Draws buffer line n from viewport row `row` onwards, returns the number of rows used
+    fn draw_line(&mut self, n: usize, row: usize, height: usize) -> Result<usize> {
+        let gutter = self.gutter_width();
+        let width = self.text_width()?;
+        let (len, cells) = match self.subed.line_text(n) {
+            Some(line) => (line.chars().count(), layout::cells(&line, self.indent.width)),
+            None => {
+                self.term.execute(cursor::MoveTo(0, (ROW_OFFSET + row) as u16))?;
+                self.term.execute(terminal::Clear(terminal::ClearType::CurrentLine))?;
+                return Ok(1);
+            }
+        };
+
+        let highlights = self.highlights(n);
+        // the cells of each row, with the screen column the row starts at
+        let rows: Vec<(&[Cell], usize)> = if self.wrap {
+            let rows = layout::rows(&cells, width);
+            rows.iter().enumerate().map(|(i, &(first, x))| {
+                let end = rows.get(i + 1).map_or(cells.len(), |next| next.0);
+                (&cells[first..end], x)
+            }).collect()
+        } else {
+            let first = cells.iter().position(|cell| cell.x + cell.width > self.left).unwrap_or(cells.len());
+            let end = cells.iter().position(|cell| cell.x >= self.left + width).unwrap_or(cells.len());
+            vec![(&cells[first..end.max(first)], self.left)]
+        };
+
+        for (i, &(row_cells, x)) in rows.iter().enumerate() {
+            if row + i >= height { break; }
+            self.term.execute(cursor::MoveTo(0, (ROW_OFFSET + row + i) as u16))?;
+            self.term.execute(terminal::Clear(terminal::ClearType::CurrentLine))?;
             self.term.execute(style::SetForegroundColor(style::Color::White))?;
-            print!("{:^lwidth$} ", i+1, lwidth=COL_OFFSET-1);
+            if i == 0 {
+                print!("{:^lwidth$} ", n+1, lwidth=gutter-1);
+            } else {
+                print!("{:^lwidth$} ", WRAP_MARKER, lwidth=gutter-1);
+            }
             self.term.execute(style::ResetColor)?;
-            print!("{}",line.show());
-            self.term.execute(cursor::MoveToNextLine(1))?;
+
+            self.print_cells(row_cells, x, x + width, &highlights)?;
+            // a selected line break is shown as a selected blank after the line
+            let end_x = layout::line_width(&cells);
+            let line_end = i + 1 == rows.len() && x <= end_x && end_x < x + width;
+            if line_end && highlights.iter().any(|&(from, to, hl)| hl == Highlight::Selection && from <= len && to > len) {
+                self.set_highlight(Some(Highlight::Selection))?;
+                print!(" ");
+                self.set_highlight(None)?;
+            }
+        }
+
+        Ok(rows.len())
+    }
+
+    // Column ranges (end excluded) of line n that are shown highlighted
+    fn highlights(&self, n: usize) -> Vec<(usize, usize, Highlight)> {
+        let mut highlights = Vec::new();
+        if let (true, Some(matcher)) = (self.searching, &self.matcher) {
+            for (from, to) in self.subed.find_in_line(n, matcher) {
+                highlights.push((from, to, Highlight::Match));
+            }
+        }
+        // pushed last so that it wins over the others
+        if let Some((start, end)) = self.subed.selection() {
+            if start.0 <= n && n <= end.0 {
+                let from = if n == start.0 { start.1 } else { 0 };
+                let to = if n == end.0 { end.1 } else { usize::MAX };
+                highlights.push((from, to, Highlight::Selection));
+            }
+        }
+        highlights
+    }
+
+    fn set_highlight(&mut self, hl: Option<Highlight>) -> Result<()> {
+        self.term.execute(style::SetAttribute(style::Attribute::Reset))?;
+        match hl {
+            Some(Highlight::Selection) => {
+                self.term.execute(style::SetAttribute(style::Attribute::Reverse))?;
+            }
+            Some(Highlight::Match) => {
+                self.term.execute(style::SetBackgroundColor(style::Color::DarkYellow))?;
+                self.term.execute(style::SetForegroundColor(style::Color::Black))?;
+            }
+            None => {}
         }
-        self.term.execute(cursor::RestorePosition)?; 
-        
         Ok(())
     }
 
-    pub fn show_post_content(&mut self) -> Result<()> {
-        self.term.execute(cursor::SavePosition)?;        
-        let cnum = self.subed.curr_line_num();
-        for (i,line) in self.subed.get_post_lines().iter().rev().enumerate() {
-            self.term.execute(style::SetForegroundColor(style::Color::White))?;
-            print!("{:^lwidth$} ", i + 2 + cnum, lwidth=COL_OFFSET-1);
-            self.term.execute(style::ResetColor)?;
-            print!("{}",line.show());
-            self.term.execute(cursor::MoveToNextLine(1))?;
+    // Prints the cells that are between screen columns `from` and `to` of their line, styling the
+    // highlighted ones. A wide char cut at either end is shown as blanks.
+    fn print_cells(&mut self, cells: &[Cell], from: usize, to: usize, highlights: &[(usize, usize, Highlight)]) -> Result<()> {
+        let mut current = None;
+        let mut segment = String::new();
+        for cell in cells {
+            let hl = highlights.iter().rev().find(|&&(start, end, _)| start <= cell.col && cell.col < end).map(|&(_, _, hl)| hl);
+            if hl != current {
+                print!("{}", segment);
+                segment.clear();
+                self.set_highlight(hl)?;
+                current = hl;
+            }
+            if cell.x < from || cell.x + cell.width > to {
+                let visible = (cell.x + cell.width).min(to) - cell.x.max(from);
+                segment.push_str(&" ".repeat(visible));
+            } else {
+                segment.push_str(&cell.text);
+            }
         }
+        print!("{}", segment);
+        if current.is_some() { self.set_highlight(None)?; }
+
+        Ok(())
+    }
+
+    // Draws buffer line n again after an edit that did not change the line count
+    pub fn show_line(&mut self, n: usize) -> Result<()> {
+        if self.wrap { return self.show_content(); } // the following lines may have shifted
+
+        let height = self.text_height()?;
+        if n < self.top || n >= self.top + height { return Ok(()); }
+        self.term.execute(cursor::SavePosition)?;
+        self.draw_line(n, n - self.top, height)?;
         self.term.execute(cursor::RestorePosition)?;
-        
+
         Ok(())
     }
 
-    pub fn start(&mut self) -> Result<()> {
+    pub fn show_content(&mut self) -> Result<()> {
+        self.term.execute(cursor::SavePosition)?;
+        let height = self.text_height()?;
+        let mut row = 0;
+        let mut n = self.top;
+        while row < height {
+            row += self.draw_line(n, row, height)?;
+            n += 1;
+        }
+        self.term.execute(cursor::RestorePosition)?;
 
-        self.init()?;
+        Ok(())
+    }
+
+    pub fn set_status(&mut self, msg: &str) {
+        self.status = msg.to_string();
+    }
+
+    pub fn show_status(&mut self) -> Result<()> {
+        let (cols, rows) = terminal::size()?;
+        self.term.execute(cursor::SavePosition)?;
+        self.term.execute(cursor::MoveTo(0, rows - 1))?;
+        self.term.execute(terminal::Clear(terminal::ClearType::CurrentLine))?;
+        self.term.execute(style::SetForegroundColor(style::Color::White))?;
+        print!("{}", layout::fit(&self.status, cols as usize));
+
+        // the file format goes on the right, when there is room left for it
+        let format = self.subed.format().describe();
+        let used = layout::width(&self.status) + format.len() + 1;
+        if used <= cols as usize {
+            self.term.execute(cursor::MoveTo((cols as usize - format.len()) as u16, rows - 1))?;
+            self.term.execute(style::SetForegroundColor(style::Color::DarkGrey))?;
+            print!("{}", format);
+        }
+        self.term.execute(style::ResetColor)?;
+        self.term.execute(cursor::RestorePosition)?;
+
+        Ok(())
+    }
+
+    // Reads a line of input on the status line, returns None if it was cancelled with Esc
+    pub fn prompt(&mut self, msg: &str) -> Result<Option<String>> {
+        self.prompt_with(|_| msg.to_string(), |_, _, _| Ok(false))
+    }
+
+    // Like prompt, but every key is first given to `on_key` along with the input so far, it
+    // returns true if it used the key. It is also called without a key when the input changes.
+    // The message is made again after every key.
+    pub fn prompt_with<M, F>(&mut self, msg: M, mut on_key: F) -> Result<Option<String>>
+    where M: Fn(&Self) -> String, F: FnMut(&mut Self, &str, Option<&KeyEvent>) -> Result<bool>, {
+        let mut input = String::new();
+        let (cols, rows) = terminal::size()?;
+
+        let res = loop {
+            let msg = msg(self);
+            self.set_status(&format!("{}{}", msg, input));
+            self.show_status()?;
+            let x = layout::width(&msg) + layout::width(&input);
+            self.term.execute(cursor::MoveTo(x.min(cols as usize - 1) as u16, rows - 1))?;
+
+            let key = match read()? {
+                Event::Key(key) => key,
+                Event::Paste(text) => {
+                    input.push_str(&text.replace(['\r', '\n'], " "));
+                    on_key(self, &input, None)?;
+                    continue;
+                }
+                _ => continue
+            };
+            if on_key(self, &input, Some(&key))? { continue; }
+
+            match key {
+                KeyEvent{ code: KeyCode::Enter, .. } => break Some(input),
+                KeyEvent{ code: KeyCode::Esc, .. } => break None,
+                KeyEvent{ code: KeyCode::Backspace, .. } => {
+                    input.pop();
+                    on_key(self, &input, None)?;
+                }
+                KeyEvent{ modifiers: keymod, code: KeyCode::Char(keych), .. } if keymod != KeyModifiers::CONTROL => {
+                    input.push(keych);
+                    on_key(self, &input, None)?;
+                }
+                _ => {}
+            }
+        };
+
+        self.set_status("");
+        self.show_status()?;
+        self.place_cursor()?;
+        Ok(res)
+    }
+
+    // Writes the buffer to its file and says how it went in the status line, false if it failed
+    fn save(&mut self) -> bool {
+        match self.subed.save(&self.fname, self.backup) {
+            Ok((lines, bytes)) => {
+                self.remove_swap();
+                self.swap_changes = self.subed.changes();
+                self.set_status(&format!("\"{}\" written {} lines, {} bytes", self.fname, lines, bytes));
+                true
+            }
+            Err(e) => {
+                self.set_status(&format!("Could not save \"{}\": {}", self.fname, e));
+                false
+            }
+        }
+    }
+
+    // Asks for another file name and, if saving there works, goes on editing that file
+    fn save_as(&mut self) -> Result<bool> {
+        let fname = match self.prompt("Save as: ")? {
+            Some(fname) if !fname.is_empty() && fname != STDIN => fname,
+            _ => return Ok(false)
+        };
+        let old = std::mem::replace(&mut self.fname, fname);
+        if !self.save() {
+            self.fname = old;
+            return Ok(false);
+        }
+        self.indent.lang = indent::Language::for_path(&self.fname);
+        if old != STDIN { fs::remove_file(swap::swap_path(Path::new(&old))).ok(); }
+        self.show_header()?;
+        Ok(true)
+    }
+
+    fn swap_path(&self) -> Option<PathBuf> {
+        // stdin buffers have no file to recover into, huge files would take too long to write
+        if self.fname == STDIN || self.subed.is_mapped() { None } else { Some(swap::swap_path(Path::new(&self.fname))) }
+    }
+
+    // Writes unsaved changes to the swap file once nothing happened for a while, or every
+    // SWAP_INTERVAL while typing goes on. Returns true if it failed and said so in the status line.
+    fn update_swap(&mut self, idle: bool) -> bool {
+        let changes = self.subed.changes();
+        if changes == self.swap_changes { return false; }
+        if !idle && self.swap_time.is_some_and(|time| time.elapsed() < SWAP_INTERVAL) { return false; }
+        self.swap_changes = changes;
+
+        // eg. undone back to the saved state
+        if !self.subed.is_modified() {
+            self.remove_swap();
+            return false;
+        }
+        let path = match self.swap_path() {
+            Some(path) => path,
+            None => return false
+        };
+        // a failing write is not retried before the next interval either
+        self.swap_time = Some(Instant::now());
+        match self.subed.write_swap(&path, Path::new(&self.fname)) {
+            Ok(()) => false,
+            Err(e) => {
+                self.set_status(&format!("Could not write the swap file: {}", e));
+                true
+            }
+        }
+    }
+
+    fn remove_swap(&mut self) {
+        if self.swap_time.take().is_some() {
+            if let Some(path) = self.swap_path() { fs::remove_file(path).ok(); }
+        }
+    }
 
+    // Offers to bring back the unsaved changes that a med which did not quit left in the swap file
+    fn recover(&mut self) -> Result<()> {
+        let path = match self.swap_path() {
+            Some(path) if !self.subed.is_readonly() => path,
+            _ => return Ok(())
+        };
+        let swap = match swap::read(&path) {
+            Ok(swap) => swap,
+            Err(_) => return Ok(())
+        };
+        if swap::running(swap.pid) {
+            // its swap file is not left over, and editing here too would write over it
+            self.subed.set_readonly(true);
+            self.set_status(&format!("\"{}\" is being edited by med (pid {}), opened read only (Alt + O makes it editable)", self.fname, swap.pid));
+            return self.refresh();
+        }
+        let lines = self.subed.lines();
+        if swap.lines == lines {
+            // nothing was lost
+            fs::remove_file(&path).ok();
+            return Ok(());
+        }
+
+        let msg = format!("Found unsaved changes left by med (pid {}): (r)ecover, (d)iff, (x) delete ", swap.pid);
+        loop {
+            match self.ask(&msg, "rdx")? {
+                Some('r') => {
+                    self.subed.set_text(&swap.lines.join("\n"));
+                    // the swap file is up to date with the buffer now
+                    self.swap_changes = self.subed.changes();
+                    self.swap_time = Some(Instant::now());
+                    self.set_status("Recovered the unsaved changes, save to keep them");
+                    break;
+                }
+                Some('d') => self.show_diff(&lines, &swap.lines, &path)?,
+                Some('x') => {
+                    fs::remove_file(&path)?;
+                    self.set_status("Deleted the swap file");
+                    break;
+                }
+                _ => {}
+            }
+        }
+        self.refresh()
+    }
+
+    // Shows how the swap file differs from the file, in a read only view of its own
+    fn show_diff(&mut self, old: &[String], new: &[String], swap_path: &Path) -> Result<()> {
+        let swap_name = swap_path.to_string_lossy().to_string();
+        let mut text = vec![format!("--- {}", self.fname), format!("+++ {}", swap_name)];
+        text.extend(diff::unified(old, new, 3));
+
+        let mut view = Editor::new(&mut *self.term, SubEditor::from_reader(text.join("\n").as_bytes(), Backing::Rope)?, &swap_name, true);
+        view.locked = true;
+        view.set_status("Unsaved changes in the swap file, Ctrl + Q goes back");
+        view.start()?;
+        self.refresh()
+    }
+
+    // Offers to show a file that does not look like text as a hex dump instead
+    fn offer_hex(&mut self) -> Result<()> {
+        let bytes = match self.binary.take() {
+            Some(bytes) => bytes,
+            None => return Ok(())
+        };
+        let msg = format!("\"{}\" looks like a binary file: (h)ex view, (t)ext ", self.fname);
+        if self.ask(&msg, "ht")? == Some('h') {
+            self.subed = SubEditor::with_lines(hex::dump(&bytes).into_iter(), Format::default());
+            self.subed.set_readonly(true);
+            self.locked = true;
+            self.set_status("Hex view, read only");
+        }
+        self.refresh()
+    }
+
+    // The path of an entry of the directory listing, going up is the parent itself rather than `dir/..`
+    fn dir_entry(&self, name: &str) -> String {
+        let path = Path::new(&self.fname).join(name.trim_end_matches('/'));
+        let path = if name == "../" { fs::canonicalize(&path).unwrap_or(path) } else { path };
+        path.to_string_lossy().to_string()
+    }
+
+    // Makes the buffer editable or read only again, views that are not the text of a file stay read only
+    fn toggle_readonly(&mut self) -> Result<()> {
+        if self.locked {
+            self.set_status("This view cannot be edited");
+            return Ok(());
+        }
+        let readonly = !self.subed.is_readonly();
+        self.subed.set_readonly(readonly);
+        self.set_status(if readonly { "The buffer is read only now" } else { "The buffer can be edited now" });
+        self.show_header()
+    }
+
+    // Switches the line endings, final newline or BOM the file is saved with
+    fn convert_format(&mut self) -> Result<()> {
+        let mut format = self.subed.format();
+        let choice = self.ask("Convert to (l)f, (c)rlf, toggle final (n)ewline, toggle (b)om ", "lcnb")?;
+        match choice {
+            Some('l') => format.crlf = false,
+            Some('c') => format.crlf = true,
+            Some('n') => format.final_newline = !format.final_newline,
+            Some('b') => format.bom = !format.bom,
+            _ => return Ok(())
+        }
+        self.subed.set_format(format);
+        self.set_status(&format!("The file will be saved with {}", format.describe()));
+        Ok(())
+    }
+
+    fn convert_encoding(&mut self) -> Result<()> {
+        let mut format = self.subed.format();
+        format.encoding = match self.ask("Convert to (u)tf-8, utf-16 (l)e, utf-16 (b)e, lat(i)n-1 ", "ulbi")? {
+            Some('u') => Encoding::Utf8,
+            Some('l') => Encoding::Utf16Le,
+            Some('b') => Encoding::Utf16Be,
+            Some('i') => Encoding::Latin1,
+            _ => return Ok(())
+        };
+        if let Some(line) = self.subed.unencodable_line(format.encoding) {
+            self.set_status(&format!("Line {} has characters that {} cannot hold", line + 1, format.encoding.name()));
+            return Ok(());
+        }
+        self.subed.set_format(format);
+        self.set_status(&format!("The file will be saved with {}", format.describe()));
+        Ok(())
+    }
+
+    // Asks what to do with unsaved changes, true if the editor can close
+    fn confirm_quit(&mut self) -> Result<bool> {
+        if !self.subed.is_modified() { return Ok(true); }
+        match self.ask("Unsaved changes: (s)ave, (d)iscard, (c)ancel ", "sdc")? {
+            // stdin buffers have no file to write back to
+            Some('s') if self.fname == STDIN => self.save_as(),
+            Some('s') => Ok(self.save()),
+            Some('d') => Ok(true),
+            _ => Ok(false)
+        }
+    }
+
+    // Asks a question on the status line and waits for one of the keys in `choices`, None on Esc
+    pub fn ask(&mut self, msg: &str, choices: &str) -> Result<Option<char>> {
+        self.set_status(msg);
+        self.show_status()?;
+        let (cols, rows) = terminal::size()?;
+        self.term.execute(cursor::MoveTo(layout::width(msg).min(cols as usize - 1) as u16, rows - 1))?;
+
+        let res = loop {
+            match read()? {
+                Event::Key(KeyEvent{ code: KeyCode::Esc, .. }) => break None,
+                Event::Key(KeyEvent{ code: KeyCode::Char(keych), .. }) if choices.contains(keych.to_ascii_lowercase()) => {
+                    break Some(keych.to_ascii_lowercase());
+                }
+                _ => {}
+            }
+        };
+
+        self.set_status("");
+        self.show_status()?;
+        self.place_cursor()?;
+        Ok(res)
+    }
+
+    fn set_search(&mut self, pattern: &str) {
+        self.search.pattern = pattern.to_string();
+        self.matcher = self.search.compile();
+    }
+
+    // Alt + R, Alt + C and Alt + W turn regex, ignore case and whole word on and off
+    fn toggle_search_option(&mut self, key: &KeyEvent) -> bool {
+        if key.modifiers != KeyModifiers::ALT { return false; }
+        match key.code {
+            KeyCode::Char('r') => self.search.regex = !self.search.regex,
+            KeyCode::Char('c') => self.search.ignore_case = !self.search.ignore_case,
+            KeyCode::Char('w') => self.search.whole_word = !self.search.whole_word,
+            _ => return false
+        }
+        self.matcher = self.search.compile();
+        true
+    }
+
+    fn search_prompt(&self, action: &str) -> String {
+        let invalid = if self.matcher.is_none() && !self.search.pattern.is_empty() { " (invalid)" } else { "" };
+        format!("{}{}{}: ", action, self.search.flags(), invalid)
+    }
+
+    // Moves the cursor to the first match at or after `origin`, or back to `origin` if there is none
+    fn search_from(&mut self, origin: (usize, usize)) {
+        self.subed.move_to(origin);
+        if let Some(matcher) = &self.matcher {
+            if let Some(found) = self.subed.find(matcher, origin, true) { self.subed.move_to(found); }
+        }
+    }
+
+    // Moves to the next (or previous) match of the last search, returns false if there is none
+    fn find_next(&mut self, forward: bool) -> bool {
+        let matcher = match &self.matcher {
+            Some(matcher) => matcher,
+            None => return false
+        };
+        let pos = self.subed.pos();
+        let from = if forward { (pos.0, pos.1 + 1) } else { pos };
+        match self.subed.find(matcher, from, forward) {
+            Some(found) => {
+                let wrapped = if forward { found <= pos } else { found >= pos };
+                if wrapped {
+                    self.set_status(&format!("Search for '{}' wrapped around", self.search.pattern));
+                }
+                self.subed.clear_selection();
+                self.subed.move_to(found);
+                true
+            }
+            None => {
+                self.set_status(&format!("No matches for '{}'", self.search.pattern));
+                false
+            }
+        }
+    }
+
+    // Searches as the pattern is typed, Enter / F3 and Shift + F3 go to the next and previous match
+    fn find(&mut self) -> Result<()> {
+        let origin = self.subed.pos();
+        self.searching = true;
+
+        self.prompt_with(|ed| ed.search_prompt("Find"), |ed, input, key| {
+            let used = match key {
+                None => {
+                    ed.set_search(input);
+                    ed.search_from(origin);
+                    true
+                }
+                Some(KeyEvent{ code: KeyCode::Enter, .. }) | Some(KeyEvent{ code: KeyCode::F(3), modifiers: KeyModifiers::NONE, .. }) => {
+                    ed.find_next(true);
+                    true
+                }
+                Some(KeyEvent{ code: KeyCode::F(3), modifiers: KeyModifiers::SHIFT, .. }) => {
+                    ed.find_next(false);
+                    true
+                }
+                Some(key) if ed.toggle_search_option(key) => {
+                    ed.search_from(origin);
+                    true
+                }
+                _ => false
+            };
+            if used {
+                ed.scroll()?;
+                ed.show_content()?;
+            }
+            Ok(used)
+        })?;
+
+        self.searching = false;
+        Ok(())
+    }
+
+    // Asks for a pattern and its replacement, then replaces every match at once or asks about each one
+    fn replace(&mut self) -> Result<()> {
+        let origin = self.subed.pos();
+        self.searching = true;
+        let res = self.replace_matches(origin);
+        self.searching = false;
+        res
+    }
+
+    fn replace_matches(&mut self, origin: (usize, usize)) -> Result<()> {
+        let pattern = self.prompt_with(|ed| ed.search_prompt("Replace"), |ed, input, key| {
+            let used = match key {
+                None => {
+                    ed.set_search(input);
+                    true
+                }
+                Some(key) => ed.toggle_search_option(key)
+            };
+            if used {
+                ed.search_from(origin);
+                ed.scroll()?;
+                ed.show_content()?;
+            }
+            Ok(used)
+        })?;
+        if pattern.is_none() { return Ok(()); }
+        let matcher = match self.search.compile() {
+            Some(matcher) => matcher,
+            None => {
+                self.set_status(&format!("Can not search for '{}'", self.search.pattern));
+                return Ok(());
+            }
+        };
+
+        let with = match self.prompt(&format!("Replace '{}' with: ", self.search.pattern))? {
+            Some(with) => with,
+            None => return Ok(())
+        };
+
+        match self.ask("Replace (a)ll or (c)onfirm each? ", "ac")? {
+            Some('a') => {
+                let count = self.subed.replace_all(&matcher, &with);
+                self.set_status(&format!("Replaced {} matches", count));
+            }
+            Some(_) => {
+                let count = self.confirm_replace(&matcher, &with, origin)?;
+                self.set_status(&format!("Replaced {} matches", count));
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    // Goes through the matches from `origin` to the end and around, asking about each one
+    fn confirm_replace(&mut self, matcher: &Matcher, with: &str, origin: (usize, usize)) -> Result<usize> {
+        let mut count = 0;
+        let mut walk = Replacing::new(origin);
+        let mut all = false;
+
+        while let Some(found) = self.subed.next_replace(matcher, &mut walk) {
+            self.subed.move_to(found);
+            self.scroll()?;
+            self.show_content()?;
+            self.place_cursor()?;
+
+            let answer = match all {
+                true => Some('y'),
+                false => self.ask("Replace this match? (y)es (n)o (a)ll remaining (q)uit ", "ynaq")?
+            };
+            match answer {
+                Some('y') | Some('a') => {
+                    all = answer == Some('a') || all;
+                    if self.subed.replace_next(matcher, &mut walk, found, Some(with)) { count += 1; }
+                }
+                Some('n') => { self.subed.replace_next(matcher, &mut walk, found, None); }
+                _ => break
+            }
+        }
+        Ok(count)
+    }
+
+    // Shift extends the selection with the cursor move that follows, any other move drops it.
+    // Returns the modifiers without Shift.
+    fn select(&mut self, keymod: KeyModifiers) -> KeyModifiers {
+        if keymod.contains(KeyModifiers::SHIFT) {
+            self.subed.start_selection();
+        } else {
+            self.subed.clear_selection();
+        }
+        keymod - KeyModifiers::SHIFT
+    }
+
+    // Removes the selected text, returns false if nothing was selected
+    fn delete_selection(&mut self) -> bool {
+        match self.subed.selection() {
+            Some((start, end)) => {
+                self.subed.remove_range(start, end);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn copy(&mut self) -> bool {
+        match self.subed.selection() {
+            Some((start, end)) => {
+                let text = self.subed.get_range(start, end);
+                let target = match self.clipboard.copy(&text, self.term) {
+                    clipboard::Target::Command => "system clipboard",
+                    clipboard::Target::Terminal => "terminal clipboard",
+                    clipboard::Target::Internal => "internal clipboard"
+                };
+                self.set_status(&format!("Copied {} chars to the {}", text.chars().count(), target));
+                true
+            }
+            None => false
+        }
+    }
+
+    fn show_history_position(&mut self) {
+        let (state, states, ago) = self.subed.history_position();
+        self.set_status(&format!("State {} of {}, {}", state, states, fmt_ago(ago)));
+    }
+
+    // Asks how far to go through the history, eg. -5m for 5 minutes back or +3 for 3 states forward
+    fn time_travel(&mut self) -> Result<bool> {
+        let spec = match self.prompt("Time travel (eg. -5m, +30s, -3): ")? {
+            Some(spec) => spec,
+            None => return Ok(false)
+        };
+        let moved = match parse_travel(&spec) {
+            Some(Travel::Steps(steps)) => self.subed.time_travel(steps),
+            Some(Travel::Secs(secs)) => self.subed.time_travel_secs(secs),
+            None => {
+                self.set_status(&format!("Can not travel by '{}'", spec));
+                return Ok(false);
+            }
+        };
+        self.show_history_position();
+        Ok(moved)
+    }
+
+    pub fn place_cursor(&mut self) -> Result<()> {
+        let (row, col) = self.cursor_pos()?;
+        let col = self.gutter_width() + col;
+        self.term.execute(cursor::MoveTo(col as u16, (ROW_OFFSET + row) as u16))?;
+
+        Ok(())
+    }
+
+    pub fn refresh(&mut self) -> Result<()> {
+        self.scroll()?;
         self.term.execute(terminal::Clear(terminal::ClearType::All))?;
         self.show_content()?;
         self.show_header()?;
-        self.term.execute(cursor::MoveTo(COL_OFFSET as u16, ROW_OFFSET as u16))?;
+        self.show_status()?;
+        self.place_cursor()
+    }
+
+    // Moves the viewport by one line, dragging the cursor along if it would leave the screen
+    fn scroll_view_up(&mut self) -> Result<bool> {
+        if self.top == 0 { return Ok(false); }
+        self.top -= 1;
+        let height = self.text_height()?;
+        while self.cursor_pos()?.0 >= height && self.subed.move_up() { }
+        Ok(true)
+    }
+
+    fn scroll_view_down(&mut self) -> Result<bool> {
+        if self.top + 1 >= self.subed.num_lines() { return Ok(false); }
+        self.top += 1;
+        if self.subed.curr_line_num() < self.top { self.subed.move_down(); }
+        Ok(true)
+    }
+
+    fn page_up(&mut self) -> Result<()> {
+        let height = self.text_height()?;
+        let x = self.cursor_x();
+        self.top = self.top.saturating_sub(height);
+        for _ in 0..height { if !self.subed.move_up() { break; } }
+        self.move_to_x(x);
+        Ok(())
+    }
+
+    fn page_down(&mut self) -> Result<()> {
+        let height = self.text_height()?;
+        let x = self.cursor_x();
+        self.top = (self.top + height).min(self.subed.num_lines() - 1);
+        for _ in 0..height { if !self.subed.move_down() { break; } }
+        self.move_to_x(x);
+        Ok(())
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+
+        self.init()?;
+        self.refresh()?;
+        self.recover()?;
+        self.offer_hex()?;
 
         loop {
-            // Wait up to 1s for another event
-            if poll(Duration::from_millis(1_000))? {
+            // Wait up to 1s for another event, less while lines are being indexed
+            let timeout = if self.subed.indexing().is_some() { INDEX_INTERVAL } else { Duration::from_millis(1_000) };
+            let (gutter, lines) = (self.gutter_width(), self.subed.num_lines());
+            if poll(timeout)? {
+                let mut redraw = Redraw::Nothing;
+                let selection = self.subed.selection();
+                let modified = self.subed.is_modified();
+                self.set_status("");
+
                 // It's guaranteed that read() wont block if `poll` returns `Ok(true)`
                 match read() {
-                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::Left })) => {
-                        if keymod == KeyModifiers::CONTROL {
+                    Ok(Event::Key(KeyEvent{ code: KeyCode::Enter, .. })) if self.dir => {
+                        let name = self.subed.get_line(self.subed.curr_line_num()).map_or(String::new(), |line| line.show());
+                        self.next = Some(self.dir_entry(&name));
+                        break;
+                    }
+                    Ok(Event::Key(key)) if self.subed.is_readonly() && edits(&key) => {
+                        let msg = if self.locked { "This view cannot be edited" } else { "The buffer is read only, Alt + O makes it editable" };
+                        self.set_status(msg);
+                    }
+                    Ok(Event::Paste(_)) if self.subed.is_readonly() => {
+                        self.set_status("The buffer is read only, Alt + O makes it editable");
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: KeyModifiers::ALT, code: KeyCode::Char('o'), .. })) => {
+                        self.toggle_readonly()?;
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::Left, .. })) => {
+                        let keymod = self.select(keymod);
+                        if keymod == KeyModifiers::ALT {
+                            if self.subed.time_travel(-1) { redraw = Redraw::All; }
+                            self.show_history_position();
+                        } else if keymod == KeyModifiers::CONTROL {
                             self.subed.move_start();
-                            self.term.execute(cursor::MoveToColumn(COL_OFFSET as u16 + 1))?;
-                        } else if self.subed.move_left() {
-                            self.term.execute(cursor::MoveLeft(1))?;
+                        } else {
+                            self.subed.move_left();
                         }
                     }
-                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::Right })) => {
-                        if keymod == KeyModifiers::CONTROL {
+                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::Right, .. })) => {
+                        let keymod = self.select(keymod);
+                        if keymod == KeyModifiers::ALT {
+                            if self.subed.time_travel(1) { redraw = Redraw::All; }
+                            self.show_history_position();
+                        } else if keymod == KeyModifiers::CONTROL {
                             self.subed.move_end();
-                            self.term.execute(cursor::MoveToColumn(COL_OFFSET as u16 + self.subed.linelen() as u16 + 1))?;
-                        } else if self.subed.move_right() {
-                            self.term.execute(cursor::MoveRight(1))?;
+                        } else {
+                            self.subed.move_right();
                         }
                     }
-                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::Up })) => {
+                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::Up, .. })) => {
+                        let keymod = self.select(keymod);
+                        if keymod == KeyModifiers::CONTROL {
+                            if self.scroll_view_up()? { redraw = Redraw::All; }
+                        } else {
+                            let x = self.cursor_x();
+                            if self.subed.move_up() { self.move_to_x(x); }
+                        }
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::Down, .. })) => {
+                        let keymod = self.select(keymod);
+                        if keymod == KeyModifiers::CONTROL {
+                            if self.scroll_view_down()? { redraw = Redraw::All; }
+                        } else {
+                            let x = self.cursor_x();
+                            if self.subed.move_down() { self.move_to_x(x); }
+                        }
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::PageUp, .. })) => {
+                        self.select(keymod);
+                        self.page_up()?;
+                        redraw = Redraw::All;
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::PageDown, .. })) => {
+                        self.select(keymod);
+                        self.page_down()?;
+                        redraw = Redraw::All;
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::Home, .. })) => {
+                        let keymod = self.select(keymod);
                         if keymod == KeyModifiers::CONTROL {
                             self.subed.move_first();
-                            self.term.execute(cursor::MoveTo(COL_OFFSET as u16, ROW_OFFSET as u16))?;
-                        } else if self.subed.move_up() {
-                            self.term.execute(cursor::MoveToPreviousLine(1))?;
-                            self.term.execute(cursor::MoveToColumn((COL_OFFSET + self.subed.cursor() + 1) as u16))?;                            
+                        } else {
+                            self.subed.move_start();
                         }
                     }
-                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::Down })) => {
+                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::End, .. })) => {
+                        let keymod = self.select(keymod);
                         if keymod == KeyModifiers::CONTROL {
                             self.subed.move_last();
-                            self.term.execute(cursor::MoveTo(COL_OFFSET as u16, ROW_OFFSET as u16 + self.subed.num_lines() as u16 - 1))?;
-                        } else if self.subed.move_down() {
-                            self.term.execute(cursor::MoveToNextLine(1))?;
-                            self.term.execute(cursor::MoveToColumn((COL_OFFSET + self.subed.cursor() + 1) as u16))?;                            
+                        } else {
+                            self.subed.move_end();
                         }
                     }
-                    Ok(Event::Key(KeyEvent{ modifiers: _keymod, code: KeyCode::Enter })) => {
-                        let prevline = self.subed.insert_newline();
-                        self.term.execute(terminal::Clear(terminal::ClearType::CurrentLine))?;
-                        self.term.execute(terminal::Clear(terminal::ClearType::FromCursorDown))?;
-                        
-                        self.term.execute(cursor::MoveToColumn(0))?;
-                        self.term.execute(style::SetForegroundColor(style::Color::White))?;
-                        print!("{:^lwidth$} ", self.subed.curr_line_num(), lwidth=COL_OFFSET-1);
-                        self.term.execute(style::ResetColor)?;
-                        print!("{}", prevline);
-                        self.term.execute(cursor::MoveToNextLine(1))?;
-
-                        self.term.execute(style::SetForegroundColor(style::Color::White))?;
-                        print!("{:^lwidth$} ", self.subed.curr_line_num()+1, lwidth=COL_OFFSET-1);
-                        self.term.execute(style::ResetColor)?;
-                        print!("{}", self.subed.curr_line());
-
-                        self.term.execute(cursor::MoveToNextLine(1))?;
-                        self.show_post_content()?;
-                        self.term.execute(cursor::MoveToPreviousLine(1))?;
-                        self.term.execute(cursor::MoveToColumn((COL_OFFSET + self.subed.cursor() + 1) as u16))?;                            
-                    } 
-                    Ok(Event::Key(KeyEvent{ modifiers: _keymod, code: KeyCode::Backspace })) => {
-                        match self.subed.backspace() {
-                            subeditor::DEL::NewLine(newcursor) => {
-                                self.term.execute(terminal::Clear(terminal::ClearType::CurrentLine))?;
-                                self.term.execute(terminal::Clear(terminal::ClearType::FromCursorDown))?;
-                                
-                                self.term.execute(cursor::MoveToPreviousLine(1))?; 
-                                self.term.execute(style::SetForegroundColor(style::Color::White))?;
-                                print!("{:^lwidth$} ", self.subed.curr_line_num()+1, lwidth=COL_OFFSET-1);
-                                self.term.execute(style::ResetColor)?;        
-
-                                print!("{}", self.subed.curr_line());
-                                self.term.execute(cursor::MoveToNextLine(1))?;
-                                self.show_post_content()?;
-                                self.term.execute(cursor::MoveToPreviousLine(1))?;
-                                self.term.execute(cursor::MoveToColumn(COL_OFFSET as u16 + newcursor as u16 + 1))?;
-                            } 
-                            subeditor::DEL::Yes => {
-                                self.term.execute(cursor::MoveLeft(1))?;
-                                self.term.execute(terminal::Clear(terminal::ClearType::UntilNewLine))?;
-                                self.term.execute(cursor::SavePosition)?;                
-                                print!("{}", self.subed.show_curr_post_line());
-                                self.term.execute(cursor::RestorePosition)?;                
+                    Ok(Event::Key(KeyEvent{ modifiers: _keymod, code: KeyCode::Enter, .. })) => {
+                        self.delete_selection();
+                        self.insert_newline();
+                        redraw = Redraw::All;
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: _keymod, code: KeyCode::Backspace, .. })) => {
+                        if self.delete_selection() {
+                            redraw = Redraw::All;
+                        } else if let Some(col) = self.dedent_col() {
+                            let line = self.subed.curr_line_num();
+                            self.subed.remove_range((line, col), self.subed.pos());
+                            redraw = Redraw::Line;
+                        } else {
+                            match self.subed.backspace() {
+                                subeditor::DEL::NewLine => { redraw = Redraw::All; }
+                                subeditor::DEL::Yes => { redraw = Redraw::Line; }
+                                subeditor::DEL::No => { }
                             }
-                            subeditor::DEL::No => { }
                         }
-                    } 
-                    Ok(Event::Key(KeyEvent{ modifiers: _keymod, code: KeyCode::Delete })) => {
-                        match self.subed.delete() {
-                            subeditor::DEL::NewLine(newcursor) => {
-                                self.term.execute(terminal::Clear(terminal::ClearType::CurrentLine))?;
-                                self.term.execute(terminal::Clear(terminal::ClearType::FromCursorDown))?;
-                                self.term.execute(cursor::MoveToColumn(0))?;
-                                
-                                self.term.execute(style::SetForegroundColor(style::Color::White))?;
-                                print!("{:^lwidth$} ", self.subed.curr_line_num()+1, lwidth=COL_OFFSET-1);
-                                self.term.execute(style::ResetColor)?;        
-                                print!("{}", self.subed.curr_line());
-
-                                self.term.execute(cursor::MoveToNextLine(1))?;
-                                self.show_post_content()?;
-                                self.term.execute(cursor::MoveToPreviousLine(1))?;
-                                self.term.execute(cursor::MoveToColumn(COL_OFFSET as u16 + newcursor as u16 + 1))?;
-                            } 
-                            subeditor::DEL::Yes => {
-                                self.term.execute(terminal::Clear(terminal::ClearType::UntilNewLine))?;
-                                self.term.execute(cursor::SavePosition)?;                
-                                print!("{}", self.subed.show_curr_post_line());
-                                self.term.execute(cursor::RestorePosition)?;
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: KeyModifiers::NONE, code: KeyCode::Tab, .. })) => {
+                        if self.delete_selection() { redraw = Redraw::All; } else { redraw = Redraw::Line; }
+                        let tab = self.indent.tab(self.cursor_x());
+                        self.subed.insert_str(&tab);
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: _keymod, code: KeyCode::Delete, .. })) => {
+                        if self.delete_selection() {
+                            redraw = Redraw::All;
+                        } else {
+                            match self.subed.delete() {
+                                subeditor::DEL::NewLine => { redraw = Redraw::All; }
+                                subeditor::DEL::Yes => { redraw = Redraw::Line; }
+                                subeditor::DEL::No => { }
                             }
-                            subeditor::DEL::No => { }
                         }
-                    } 
-                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::Char(keych) })) => {
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: KeyModifiers::ALT, code: KeyCode::Char('z'), .. })) => {
+                        self.toggle_wrap()?;
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: KeyModifiers::ALT, code: KeyCode::Char('s'), .. })) => {
+                        self.save_as()?;
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: KeyModifiers::ALT, code: KeyCode::Char('f'), .. })) => {
+                        self.convert_format()?;
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: KeyModifiers::ALT, code: KeyCode::Char('e'), .. })) => {
+                        self.convert_encoding()?;
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: KeyModifiers::ALT, code: KeyCode::Char('b'), .. })) => {
+                        match self.subed.next_branch() {
+                            (_, 0) => self.set_status("No branches to redo from here"),
+                            (branch, branches) => self.set_status(&format!("Redo follows branch {} of {}", branch, branches))
+                        }
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: KeyModifiers::ALT, code: KeyCode::Char('t'), .. })) => {
+                        let moved = self.time_travel()?;
+                        if moved { redraw = Redraw::All; }
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::Char(keych), .. })) => {
                         if keymod == KeyModifiers::CONTROL && (keych == 'q' || keych == 'Q') {
-                            break;
+                            if self.confirm_quit()? {
+                                self.remove_swap();
+                                break;
+                            }
                         } else if keymod == KeyModifiers::CONTROL && (keych == 's' || keych == 'S') {
-                            self.subed.save(self.fname)?;
-                            break;
+                            if self.subed.is_readonly() {
+                                self.set_status("The buffer is read only, Alt + S saves it elsewhere");
+                            } else if self.fname == STDIN {
+                                // stdin buffers have no file to write back to
+                                self.save_as()?;
+                            } else {
+                                self.save();
+                            }
+                        } else if keymod == KeyModifiers::CONTROL && (keych == 'z' || keych == 'Z') {
+                            if self.subed.undo() { redraw = Redraw::All; }
+                        } else if keymod == KeyModifiers::CONTROL && (keych == 'y' || keych == 'Y') {
+                            if self.subed.redo() { redraw = Redraw::All; }
+                        } else if keymod == KeyModifiers::CONTROL && (keych == 'f' || keych == 'F') {
+                            self.find()?;
+                            redraw = Redraw::All;
+                        } else if keymod == KeyModifiers::CONTROL && (keych == 'r' || keych == 'R') {
+                            self.replace()?;
+                            redraw = Redraw::All;
+                        } else if keymod == KeyModifiers::CONTROL && (keych == 'a' || keych == 'A') {
+                            self.subed.select_all();
+                        } else if keymod == KeyModifiers::CONTROL && (keych == 'c' || keych == 'C') {
+                            self.copy();
+                        } else if keymod == KeyModifiers::CONTROL && (keych == 'x' || keych == 'X') {
+                            if self.copy() { self.delete_selection(); }
+                        } else if keymod == KeyModifiers::CONTROL && (keych == 'v' || keych == 'V') {
+                            self.delete_selection();
+                            let text = self.clipboard.paste();
+                            self.subed.insert_str(&text);
+                            redraw = Redraw::All;
                         } else {
-                            self.subed.insert(keych);
-                            self.term.execute(terminal::Clear(terminal::ClearType::UntilNewLine))?;
-                            print!("{}", keych);
-                            self.term.execute(cursor::SavePosition)?;                
-                            print!("{}", self.subed.show_curr_post_line());
-                            self.term.execute(cursor::RestorePosition)?;
+                            if self.delete_selection() { redraw = Redraw::All; } else { redraw = Redraw::Line; }
+                            self.insert_char(keych);
                         }
                     }
-                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::F(5) })) => {
-                        self.term.execute(terminal::Clear(terminal::ClearType::All))?;
-                        self.subed.move_first();
-                        self.show_content()?;
-                        self.show_header()?;
-                        self.term.execute(cursor::MoveTo(COL_OFFSET as u16, ROW_OFFSET as u16))?;
+                    Ok(Event::Key(KeyEvent{ modifiers: keymod, code: KeyCode::F(3), .. })) => {
+                        self.find_next(keymod != KeyModifiers::SHIFT);
+                    }
+                    Ok(Event::Key(KeyEvent{ modifiers: _keymod, code: KeyCode::F(5), .. })) => {
+                        self.refresh()?;
+                    }
+                    Ok(Event::Paste(text)) => {
+                        // the whole paste arrives at once instead of as one key per char
+                        self.delete_selection();
+                        self.subed.insert_str(&text.replace("\r\n", "\n").replace('\r', "\n"));
+                        redraw = Redraw::All;
                     }
                     Ok(Event::Resize(_,_)) => {
-                        self.show_content()?;
-                        self.show_header()?;                
+                        self.refresh()?;
                     }
                     Err(_) => {
                         // error handling
@@ -261,11 +1363,30 @@ impl Editor<'_> {
                         // nothing for mouse events, other Fn keys
                     }
                 }
-        } else {
+
+                if self.subed.selection() != selection { redraw = Redraw::All; }
+                if self.subed.is_modified() != modified { self.show_header()?; }
+                if self.scroll()? || redraw == Redraw::All {
+                    self.show_content()?;
+                } else if redraw == Redraw::Line {
+                    let curr_line = self.subed.curr_line_num();
+                    self.show_line(curr_line)?;
+                }
+                self.update_swap(false);
+                self.show_status()?;
+                self.place_cursor()?;
+            } else if self.subed.poll_index() {
+                // lines past the ones known so far may be on the screen
+                if self.gutter_width() != gutter || self.top + self.text_height()? > lines { self.show_content()?; }
+                self.show_header()?;
+                self.place_cursor()?;
+            } else if self.update_swap(true) {
                 // Timeout expired, no event for 1s
+                self.show_status()?;
+                self.place_cursor()?;
             }
-        }   
-        
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,192 @@
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+static BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Copy and paste commands tried in order when MED_COPY / MED_PASTE are not set
+static COMMANDS: &[(&str, &str, &str)] = &[
+    // (environment variable that has to be set, copy command, paste command)
+    ("WAYLAND_DISPLAY", "wl-copy", "wl-paste --no-newline"),
+    ("DISPLAY", "xclip -selection clipboard", "xclip -selection clipboard -o"),
+    ("DISPLAY", "xsel --clipboard --input", "xsel --clipboard --output"),
+    ("", "pbcopy", "pbpaste")
+];
+
+// Where copied text ended up
+#[derive(Debug, PartialEq)]
+pub enum Target {
+    Command,
+    Terminal,
+    Internal
+}
+
+// Keeps copied text in an internal register and, when it can, in the system clipboard
+#[derive(Debug)]
+pub struct Clipboard {
+    register: String,
+    copy_cmd: Option<Vec<String>>,
+    paste_cmd: Option<Vec<String>>,
+    osc52: bool
+}
+
+fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i)) & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn split_command(cmd: &str) -> Option<Vec<String>> {
+    let parts: Vec<String> = cmd.split_whitespace().map(|part| part.to_string()).collect();
+    if parts.is_empty() { None } else { Some(parts) }
+}
+
+fn in_path(program: &str) -> bool {
+    env::var_os("PATH").is_some_and(|paths| {
+        env::split_paths(&paths).any(|dir| Path::new(&dir).join(program).is_file())
+    })
+}
+
+// A command given in `var`, or else the first one of COMMANDS that is installed
+fn find_command(var: &str, pick: fn(&(&str, &'static str, &'static str)) -> &'static str) -> Option<Vec<String>> {
+    if let Ok(cmd) = env::var(var) {
+        // set but empty turns the command off
+        return split_command(&cmd);
+    }
+    COMMANDS.iter()
+        .filter(|cmds| cmds.0.is_empty() || env::var_os(cmds.0).is_some())
+        .map(pick)
+        .find(|cmd| cmd.split_whitespace().next().is_some_and(in_path))
+        .and_then(split_command)
+}
+
+fn run_copy(cmd: &[String], text: &str) -> io::Result<()> {
+    let mut child = Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(text.as_bytes())?;
+    match child.wait()?.success() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("{} failed", cmd[0])))
+    }
+}
+
+fn run_paste(cmd: &[String]) -> io::Result<String> {
+    let out = Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    match out.status.success() {
+        true => Ok(String::from_utf8_lossy(&out.stdout).to_string()),
+        false => Err(io::Error::other(format!("{} failed", cmd[0])))
+    }
+}
+
+impl Clipboard {
+
+    // Set MED_COPY / MED_PASTE to the commands to use (empty to use none)
+    // and MED_OSC52=0 to not copy through the terminal
+    pub fn from_env() -> Clipboard {
+        Clipboard {
+            register: String::new(),
+            copy_cmd: find_command("MED_COPY", |cmds| cmds.1),
+            paste_cmd: find_command("MED_PASTE", |cmds| cmds.2),
+            osc52: env::var("MED_OSC52").map_or(true, |val| val != "0")
+        }
+    }
+
+    pub fn copy(&mut self, text: &str, term: &mut dyn Write) -> Target {
+        self.register = text.to_string();
+
+        if let Some(cmd) = &self.copy_cmd {
+            if run_copy(cmd, text).is_ok() { return Target::Command; }
+        }
+        // OSC 52 asks the terminal to set the clipboard, this also works over ssh
+        if self.osc52 && write!(term, "\x1b]52;c;{}\x07", base64(text.as_bytes())).and_then(|_| term.flush()).is_ok() {
+            return Target::Terminal;
+        }
+        Target::Internal
+    }
+
+    pub fn paste(&mut self) -> String {
+        if let Some(cmd) = &self.paste_cmd {
+            if let Ok(text) = run_paste(cmd) {
+                self.register = text;
+            }
+        }
+        self.register.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn base64_known_answers() {
+        // from RFC 4648
+        let cases = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="), ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")];
+        for (data, encoded) in cases.iter() {
+            assert_eq!(base64(data.as_bytes()), *encoded);
+        }
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+    }
+
+    // The only test that touches the MED_ variables, as tests run side by side
+    #[cfg(unix)]
+    #[test]
+    fn commands_from_the_environment() {
+        let dir = env::temp_dir().join(format!("med-test-{}-clipboard", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let store = dir.join("store");
+        // a clipboard that is just a file
+        fs::write(dir.join("copy.sh"), format!("cat > {}\n", store.display())).unwrap();
+        fs::write(dir.join("paste.sh"), format!("cat {}\n", store.display())).unwrap();
+        env::set_var("MED_COPY", format!("sh {}", dir.join("copy.sh").display()));
+        env::set_var("MED_PASTE", format!("sh {}", dir.join("paste.sh").display()));
+        env::set_var("MED_OSC52", "0");
+
+        let (mut clipboard, mut term) = (Clipboard::from_env(), Vec::new());
+        assert_eq!(clipboard.copy("héllo\nworld", &mut term), Target::Command);
+        assert_eq!(fs::read_to_string(&store).unwrap(), "héllo\nworld");
+        fs::write(&store, "copied elsewhere").unwrap();
+        assert_eq!(clipboard.paste(), "copied elsewhere");
+        assert!(term.is_empty());
+
+        // commands that fail leave the text in med's own register
+        env::set_var("MED_COPY", "false");
+        env::set_var("MED_PASTE", "false");
+        let mut clipboard = Clipboard::from_env();
+        assert_eq!(clipboard.copy("kept", &mut term), Target::Internal);
+        assert_eq!(clipboard.paste(), "kept");
+        assert!(term.is_empty());
+
+        // empty turns the commands off, which leaves the terminal
+        env::set_var("MED_COPY", "");
+        env::set_var("MED_PASTE", "");
+        env::remove_var("MED_OSC52");
+        let mut clipboard = Clipboard::from_env();
+        assert_eq!(clipboard.copy("hi", &mut term), Target::Terminal);
+        assert_eq!(term, b"\x1b]52;c;aGk=\x07");
+        assert_eq!(clipboard.paste(), "hi");
+
+        for var in ["MED_COPY", "MED_PASTE"].iter() { env::remove_var(var); }
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,114 @@
+// Above this many cells the table of common lines takes too much memory, the lines that differ
+// are then shown as removed and added as a whole
+static MAX_TABLE: usize = 4_000_000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Same,
+    Removed,
+    Added
+}
+
+// What happens to each line going from `old` to `new`, with the old and new line indices
+fn diff(old: &[String], new: &[String]) -> Vec<(Op, usize, usize)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut ops: Vec<(Op, usize, usize)> = (0..prefix).map(|n| (Op::Same, n, n)).collect();
+    let (mut i, mut j) = (0, 0);
+    if a.len().saturating_mul(b.len()) <= MAX_TABLE {
+        // common[i * width + j] is the length of the longest common subsequence of a[i..] and b[j..]
+        let width = b.len() + 1;
+        let mut common = vec![0u32; (a.len() + 1) * width];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                common[i * width + j] = match a[i] == b[j] {
+                    true => common[(i + 1) * width + j + 1] + 1,
+                    false => common[(i + 1) * width + j].max(common[i * width + j + 1])
+                };
+            }
+        }
+        while i < a.len() && j < b.len() {
+            if a[i] == b[j] {
+                ops.push((Op::Same, prefix + i, prefix + j));
+                i += 1;
+                j += 1;
+            } else if common[(i + 1) * width + j] >= common[i * width + j + 1] {
+                ops.push((Op::Removed, prefix + i, prefix + j));
+                i += 1;
+            } else {
+                ops.push((Op::Added, prefix + i, prefix + j));
+                j += 1;
+            }
+        }
+    }
+    ops.extend((i..a.len()).map(|i| (Op::Removed, prefix + i, prefix + j)));
+    ops.extend((j..b.len()).map(|j| (Op::Added, prefix + a.len(), prefix + j)));
+    ops.extend((0..suffix).map(|n| (Op::Same, old.len() - suffix + n, new.len() - suffix + n)));
+    ops
+}
+
+// The differences between two versions of a text as a unified diff, with `context` lines
+// around every change
+pub fn unified(old: &[String], new: &[String], context: usize) -> Vec<String> {
+    let ops = diff(old, new);
+    let is_change = |n: &usize| ops[*n].0 != Op::Same;
+    let mut out = Vec::new();
+
+    let mut from = 0;
+    while let Some(first) = (from..ops.len()).find(is_change) {
+        // changes closer than twice the context go in the same hunk
+        let mut last = first;
+        while let Some(next) = (last + 1..ops.len()).find(is_change) {
+            if next - last > 2 * context + 1 { break; }
+            last = next;
+        }
+        let start = first.saturating_sub(context);
+        let end = (last + context + 1).min(ops.len());
+
+        let hunk = &ops[start..end];
+        let old_count = hunk.iter().filter(|op| op.0 != Op::Added).count();
+        let new_count = hunk.iter().filter(|op| op.0 != Op::Removed).count();
+        // an empty range starts at the line before it
+        let old_start = hunk[0].1 + (old_count > 0) as usize;
+        let new_start = hunk[0].2 + (new_count > 0) as usize;
+        out.push(format!("@@ -{},{} +{},{} @@", old_start, old_count, new_start, new_count));
+        for &(op, i, j) in hunk {
+            out.push(match op {
+                Op::Same => format!(" {}", old[i]),
+                Op::Removed => format!("-{}", old[i]),
+                Op::Added => format!("+{}", new[j])
+            });
+        }
+        from = end;
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.split(' ').map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn hunks() {
+        let diff = unified(&lines("a b c d e f g h"), &lines("a B c d e f g h i"), 1);
+        assert_eq!(diff, vec!["@@ -1,3 +1,3 @@", " a", "-b", "+B", " c", "@@ -8,1 +8,2 @@", " h", "+i"]);
+        // changes closer than twice the context share a hunk
+        let diff = unified(&lines("a b c d"), &lines("A b c D"), 1);
+        assert_eq!(diff, vec!["@@ -1,4 +1,4 @@", "-a", "+A", " b", " c", "-d", "+D"]);
+        assert!(unified(&lines("a b"), &lines("a b"), 3).is_empty());
+    }
+
+    #[test]
+    fn empty_ranges_start_before_them() {
+        assert_eq!(unified(&lines("a"), &lines("a b"), 0), vec!["@@ -1,0 +2,1 @@", "+b"]);
+        assert_eq!(unified(&lines("a b"), &lines("b"), 0), vec!["@@ -1,1 +0,0 @@", "-a"]);
+    }
+}
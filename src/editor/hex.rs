@@ -0,0 +1,12 @@
+// Lines of 16 bytes each as their offset, their hex values and the printable ASCII chars
+// among them, the way `hexdump -C` shows them
+pub fn dump(bytes: &[u8]) -> Vec<String> {
+    bytes.chunks(16).enumerate().map(|(i, chunk)| {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let (first, second) = hex.split_at(hex.len().min(8));
+        let ascii: String = chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }
+        }).collect();
+        format!("{:08x}  {:<23}  {:<23}  |{}|", i * 16, first.join(" "), second.join(" "), ascii)
+    }).collect()
+}
@@ -0,0 +1,131 @@
+use std::path::Path;
+
+// What indents the line after it and what dedents the line it is typed on, for a kind of file
+#[derive(Debug)]
+pub struct Language {
+    extensions: &'static [&'static str],
+    openers: &'static str, // a line ending in one of these indents the next one
+    closers: &'static str, // typed first on a line, dedents it
+    colon: bool, // a line ending in ':' indents the next one
+    spaces: bool // indented with spaces whatever the settings, as tabs are not allowed or mixing them is an error
+}
+
+static LANGUAGES: &[Language] = &[
+    Language { extensions: &["rs"], openers: "{([", closers: "})]", colon: false, spaces: false },
+    Language { extensions: &["py", "pyw"], openers: "{([", closers: "})]", colon: true, spaces: true },
+    Language { extensions: &["yml", "yaml"], openers: "{[", closers: "}]", colon: true, spaces: true }
+];
+
+// Anything else just keeps the indentation of the line before
+static PLAIN: Language = Language { extensions: &[], openers: "", closers: "", colon: false, spaces: false };
+
+impl Language {
+
+    pub fn for_path(path: &str) -> &'static Language {
+        let ext = Path::new(path).extension().map_or(String::new(), |ext| ext.to_string_lossy().to_lowercase());
+        LANGUAGES.iter().find(|lang| lang.extensions.contains(&ext.as_str())).unwrap_or(&PLAIN)
+    }
+
+    fn opens(&self, line: &str) -> bool {
+        let line = line.trim_end();
+        line.ends_with(|ch| self.openers.contains(ch)) || (self.colon && line.ends_with(':'))
+    }
+}
+
+// How indentation is typed and shown
+#[derive(Debug, Clone, Copy)]
+pub struct Indent {
+    pub width: usize, // columns of one level, which is also how wide a tab is shown
+    pub soft: bool, // if Tab inserts spaces instead of a tab
+    pub lang: &'static Language
+}
+
+impl Default for Indent {
+    fn default() -> Indent {
+        Indent { width: 4, soft: false, lang: &PLAIN }
+    }
+}
+
+fn is_blank(text: &str) -> bool {
+    text.chars().all(|ch| ch == ' ' || ch == '\t')
+}
+
+impl Indent {
+
+    pub fn new(width: usize, soft: bool, path: &str) -> Indent {
+        let lang = Language::for_path(path);
+        Indent { width, soft: soft || lang.spaces, lang }
+    }
+
+    // One level of indentation
+    fn unit(&self) -> String {
+        if self.soft { " ".repeat(self.width) } else { "\t".to_string() }
+    }
+
+    // The indentation of the line that Enter starts after `before`, the text before the cursor:
+    // the same as the line it was pressed on, one level more if `before` opens a block. The
+    // level added is made of what the line is already indented with, so the two never mix.
+    pub fn next_line(&self, before: &str) -> String {
+        let mut indent: String = before.chars().take_while(|&ch| ch == ' ' || ch == '\t').collect();
+        if self.lang.opens(before) {
+            let unit = match indent.chars().last() {
+                Some(' ') => " ".repeat(self.width),
+                Some(_) => "\t".to_string(),
+                None => self.unit()
+            };
+            indent.push_str(&unit);
+        }
+        indent
+    }
+
+    // When `ch` closes a block and is typed on a line with only indentation before it, the
+    // column that the line goes back one level to
+    pub fn close(&self, before: &str, ch: char) -> Option<usize> {
+        if !self.lang.closers.contains(ch) || before.is_empty() || !is_blank(before) { return None; }
+        let len = before.chars().count();
+        if before.ends_with('\t') { return Some(len - 1); }
+
+        // spaces go back to the previous tab stop
+        let x = before.chars().fold(0, |x, ch| if ch == '\t' { x + self.width - x % self.width } else { x + 1 });
+        let spaces = before.chars().rev().take_while(|&ch| ch == ' ').count();
+        Some(len - spaces.min(x - (x - 1) / self.width * self.width))
+    }
+
+    // What Tab inserts at screen column `x`, soft tabs go up to the next tab stop
+    pub fn tab(&self, x: usize) -> String {
+        match self.soft {
+            true => " ".repeat(self.width - x % self.width),
+            false => self.unit()
+        }
+    }
+
+    // With soft tabs, backspace after nothing but spaces goes back to the previous indent
+    // level. Returns the column it goes back to, None if it removes one char as usual.
+    pub fn dedent(&self, before: &str) -> Option<usize> {
+        if !self.soft || before.is_empty() || !before.chars().all(|ch| ch == ' ') { return None; }
+        let len = before.chars().count();
+        Some((len - 1) / self.width * self.width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn python_blocks_are_indented_with_spaces() {
+        let indent = Indent::new(4, false, "main.py");
+        assert_eq!(indent.next_line("def f():"), "    ");
+        assert_eq!(indent.next_line("    if x:"), "        ");
+        assert_eq!(indent.next_line("    x = 1"), "    ");
+    }
+
+    #[test]
+    fn the_level_added_matches_the_line() {
+        let indent = Indent::new(4, false, "main.rs");
+        assert_eq!(indent.next_line("fn main() {"), "\t");
+        assert_eq!(indent.next_line("\tif x {"), "\t\t");
+        assert_eq!(indent.next_line("    if x {"), "        ");
+        assert_eq!(Indent::new(2, true, "main.rs").next_line("\tloop {"), "\t\t");
+    }
+}
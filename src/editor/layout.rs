@@ -0,0 +1,163 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// A grapheme cluster of a line as it is shown on the screen
+pub struct Cell {
+    pub col: usize, // char column of the line it starts at
+    pub len: usize, // number of chars in it
+    pub x: usize, // screen column it starts at, counted from the start of the line
+    pub width: usize,
+    pub text: String // what is printed for it
+}
+
+// How a char that would move the terminal cursor around or not show at all is printed instead
+fn escape(ch: char) -> String {
+    match ch as u32 {
+        0..=0x1f | 0x7f => format!("^{}", (ch as u8 ^ 0x40) as char),
+        _ => format!("<{:x}>", ch as u32)
+    }
+}
+
+// Lays a line out into cells: tabs go up to the next tab stop, control chars are shown as ^X
+// and anything else that has no width of its own as <hex>
+pub fn cells(line: &str, tab_width: usize) -> Vec<Cell> {
+    let mut cells = Vec::new();
+    let (mut col, mut x) = (0, 0);
+    for grapheme in line.graphemes(true) {
+        let text = if grapheme == "\t" {
+            " ".repeat(tab_width - x % tab_width)
+        } else if grapheme.chars().any(|ch| ch.is_control()) || grapheme.width() == 0 {
+            grapheme.chars().map(escape).collect()
+        } else {
+            grapheme.to_string()
+        };
+        let width = if grapheme == "\t" { text.len() } else { text.width() };
+
+        let len = grapheme.chars().count();
+        cells.push(Cell { col, len, x, width, text });
+        col += len;
+        x += width;
+    }
+    cells
+}
+
+// Screen columns the whole line takes up
+pub fn line_width(cells: &[Cell]) -> usize {
+    cells.last().map_or(0, |cell| cell.x + cell.width)
+}
+
+// Index of the cell that starts at char column `col`, or the one after the last if it is past the end
+pub fn cell_at(cells: &[Cell], col: usize) -> usize {
+    cells.iter().position(|cell| cell.col >= col).unwrap_or(cells.len())
+}
+
+// Screen column of char column `col`
+pub fn x_of(cells: &[Cell], col: usize) -> usize {
+    cells.get(cell_at(cells, col)).map_or(line_width(cells), |cell| cell.x)
+}
+
+// Char column of the cell shown at screen column `x`, the end of the line if it is past it
+pub fn col_at(cells: &[Cell], x: usize) -> usize {
+    match cells.iter().find(|cell| x < cell.x + cell.width) {
+        Some(cell) => cell.col,
+        None => cells.last().map_or(0, |cell| cell.col + cell.len)
+    }
+}
+
+// Splits a line into rows of `width` screen columns, as (first cell, screen column it starts at).
+// A cell that does not fit at the end of a row goes to the next one, and there is always room
+// for the cursor after the last cell.
+pub fn rows(cells: &[Cell], width: usize) -> Vec<(usize, usize)> {
+    let mut rows = vec![(0, 0)];
+    for (i, cell) in cells.iter().enumerate() {
+        let start = rows[rows.len() - 1].1;
+        if cell.x + cell.width > start + width && cell.x > start {
+            rows.push((i, cell.x));
+        }
+    }
+    let end = line_width(cells);
+    if end >= rows[rows.len() - 1].1 + width {
+        rows.push((cells.len(), end));
+    }
+    rows
+}
+
+// The row that the cell at index `i` (or the end of the line) is on
+pub fn row_of(rows: &[(usize, usize)], i: usize) -> usize {
+    rows.iter().rposition(|&(first, _)| first <= i).unwrap_or(0)
+}
+
+// Screen columns a single line of text (eg. a file name) takes up
+pub fn width(text: &str) -> usize {
+    line_width(&cells(text, 1))
+}
+
+// The start of the text that fits in `width` screen columns, without splitting a wide char
+pub fn fit(text: &str, width: usize) -> String {
+    cells(text, 1).iter().take_while(|cell| cell.x + cell.width <= width).map(|cell| cell.text.as_str()).collect()
+}
+
+// The text cut down to its first `head` and last `tail` screen columns with ... in between,
+// without splitting a wide char
+pub fn shorten(text: &str, head: usize, tail: usize) -> String {
+    let cells = cells(text, 1);
+    let end = line_width(&cells);
+    let first: String = cells.iter().take_while(|cell| cell.x + cell.width <= head).map(|cell| cell.text.as_str()).collect();
+    let last: String = cells.iter().filter(|cell| cell.x >= end.saturating_sub(tail)).map(|cell| cell.text.as_str()).collect();
+    format!("{}...{}", first, last)
+}
+
+// The text in the middle of `width` screen columns
+pub fn center(text: &str, width: usize) -> String {
+    let pad = width.saturating_sub(self::width(text));
+    format!("{}{}{}", " ".repeat(pad / 2), text, " ".repeat(pad - pad / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tabs_and_control_chars() {
+        let cells = cells("a\tb\x01", 4);
+        assert_eq!(cells.iter().map(|cell| cell.text.as_str()).collect::<Vec<_>>(), vec!["a", "   ", "b", "^A"]);
+        assert_eq!(line_width(&cells), 7);
+        assert_eq!(x_of(&cells, 2), 4);
+        assert_eq!(col_at(&cells, 2), 1);
+    }
+
+    #[test]
+    fn rows_leave_room_for_the_cursor() {
+        let cells = cells("abcdef", 1);
+        assert_eq!(rows(&cells, 4), vec![(0, 0), (4, 4)]);
+        // a line that fills its last row gets an empty one for the cursor
+        assert_eq!(rows(&cells, 3), vec![(0, 0), (3, 3), (6, 6)]);
+        // a wide char that does not fit goes to the next row
+        let cells = self::cells("ab名前", 1);
+        assert_eq!(rows(&cells, 3), vec![(0, 0), (2, 2), (3, 4)]);
+        assert_eq!(row_of(&rows(&cells, 3), 3), 2);
+    }
+
+    #[test]
+    fn fit_counts_screen_columns() {
+        assert_eq!(fit("名前.txt", 5), "名前.");
+        assert_eq!(fit("名前.txt", 3), "名");
+        assert_eq!(fit("a\x01b", 2), "a");
+        assert_eq!(fit("short", 10), "short");
+    }
+
+    #[test]
+    fn shorten_keeps_wide_chars_whole() {
+        assert_eq!(shorten("abcdefghijklmnopqrstuvwxyz.txt", 10, 8), "abcdefghij...wxyz.txt");
+        // a wide char that would only half fit is left out
+        assert_eq!(shorten("長い名前のファイル名前.txt", 5, 6), "長い...前.txt");
+        assert_eq!(shorten("ééééééééé", 4, 2), "éééé...éé");
+    }
+
+    #[test]
+    fn center_counts_screen_columns() {
+        assert_eq!(center("名前", 8), "  名前  ");
+        assert_eq!(center("abc", 6), " abc  ");
+        assert_eq!(center("too long", 4), "too long");
+    }
+}
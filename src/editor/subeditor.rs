@@ -1,115 +1,417 @@
+use std::fmt;
 use std::fs::File;
 use std::path::Path;
-use std::io::{self, BufRead};
+use std::io::{self, Read, Seek, Write};
 use std::iter::Iterator;
 mod line;
+mod history;
+mod atomic;
+pub mod swap;
+pub mod search;
+pub mod format;
+pub mod piece;
+pub mod rope;
+pub mod mapped;
 use line::Line;
+use mapped::{Mapped, MappedLines};
+use history::{Edit, History, Move, Pos};
+use search::Matcher;
+use format::{Encoding, Format};
 
-// The output is wrapped in a Result to allow matching on errors
-// Returns an Iteratfalseor to the Reader of the lines of the file.
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where P: AsRef<Path>, {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+static HUGE: u64 = 64 << 20; // files from this size on are mapped instead of read whole
+static LARGE: usize = 1 << 20; // files from this size on keep the lines away from the cursor in a store
+static BATCH: usize = 4096; // lines taken from the store at a time when going through all of them
+
+fn read_bytes<R: Read>(mut reader: R) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// The start of a file, enough to tell its BOM and if it is binary, leaving the file to be read
+// from the start again
+fn first_bytes(mut file: &File) -> io::Result<Vec<u8>> {
+    let bytes = read_bytes(file.take(8000))?;
+    file.rewind()?;
+    Ok(bytes)
 }
 
+// Writes text in the given encoding, returns the number of bytes written
+fn write_encoded(out: &mut dyn Write, encoding: Encoding, text: &str, line: usize) -> io::Result<usize> {
+    let bytes = encoding.encode(text).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("line {} cannot be written in {}", line + 1, encoding.name()))
+    })?;
+    out.write_all(&bytes)?;
+    Ok(bytes.len())
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub enum DEL {
     Yes,
     No,
-    NewLine(usize)
+    NewLine
+}
+
+// What keeps the lines away from the cursor in files of LARGE bytes and more
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backing {
+    Rope,
+    PieceTable
+}
+
+// What was found at the path a buffer is opened from
+pub enum Opened {
+    File(SubEditor),
+    New, // nothing there yet, saving creates the file
+    Directory,
+    Denied, // no permission to read it
+    Binary(Vec<u8>),
+    TooLarge(&'static str) // a huge file that is not UTF-8 text (eg. binary), so it cannot be mapped
+}
+
+// Where a replace that asks about each match is, going from `origin` to the end of the buffer
+// and around back to it
+#[derive(Debug)]
+pub struct Replacing {
+    origin: Pos,
+    from: Pos, // where the next match is searched from
+    wrapped: bool
+}
+
+impl Replacing {
+    pub fn new(origin: Pos) -> Replacing {
+        Replacing { origin, from: origin, wrapped: false }
+    }
+}
+
+// The text operations of SubEditor in terms of positions rather than of the cursor. Other ways
+// of storing the text (eg. the piece table) offer them too, so that they can be put in its place
+// and measured against it (see benches/buffers.rs).
+pub trait Buffer {
+    fn num_lines(&self) -> usize;
+    fn line(&self, n: usize) -> Option<String>;
+    fn get_range(&self, start: Pos, end: Pos) -> String;
+    fn insert_at(&mut self, pos: Pos, text: &str);
+    fn remove_range(&mut self, start: Pos, end: Pos) -> String;
+}
+
+// Where a buffer keeps its lines away from the cursor, which prelines and postlines then only
+// hold a window of
+pub trait Store: fmt::Debug {
+    fn num_lines(&self) -> usize;
+    // Lines `from` to `to`, fewer past the last line
+    fn lines(&self, from: usize, to: usize) -> Vec<String>;
+    // Replaces lines `from` to `to`
+    fn replace(&mut self, from: usize, to: usize, lines: &[String]);
+
+    // The lines from n on that are still as they are in the file, as its bytes with their line
+    // endings, if those are `newline`. Otherwise the number of lines from n on that are not.
+    fn run(&self, n: usize, _newline: &str) -> Result<(&[u8], usize), usize> {
+        Err(self.num_lines() - n)
+    }
+
+    // Line n as it is in the file, without its line ending, if it was not edited
+    fn raw(&self, _n: usize) -> Option<&[u8]> { None }
+
+    // For a store that is still being read in the background: how far it got in percent, taking
+    // in what was read since the last poll and waiting until it is done
+    fn progress(&self) -> Option<usize> { None }
+    fn poll(&mut self) -> bool { false }
+    fn wait(&mut self) {}
 }
 
 #[derive(Debug)]
 pub struct SubEditor {
     prelines: Vec<Line>,
-    postlines: Vec<Line>
+    postlines: Vec<Line>,
+    history: History,
+    saved: usize, // the history state the file on disk is in
+    format: Format,
+    saved_format: Format,
+    anchor: Option<Pos>, // the other end of the selection, the cursor being one end
+    readonly: bool, // edits are refused, moving around and selecting still work
+    // with a store, prelines and postlines only hold the lines around the cursor
+    store: Option<Box<dyn Store>>,
+    mapped: bool, // the store is a huge file, read as it is needed
+    head: usize, // lines of the store before prelines
+    tail: usize, // first line of the store after postlines
+    edited: bool, // prelines and postlines may differ from the lines of the store they were read from
+    mangled: bool // a line that is not valid UTF-8 was edited, its text has U+FFFD for the bytes
 }
 
 impl SubEditor {
 
-    pub fn open(path: &str) -> Result<SubEditor, io::Error> {
+    pub fn with_lines<I>(lines: I, format: Format) -> SubEditor
+    where I: Iterator<Item = String>, {
 
         let mut subed = SubEditor {
             prelines: Vec::new(),
-            postlines: Vec::new()
+            postlines: Vec::new(),
+            history: History::init(),
+            saved: 0,
+            format,
+            saved_format: format,
+            anchor: None,
+            readonly: false,
+            store: None,
+            mapped: false,
+            head: 0,
+            tail: 0,
+            edited: false,
+            mangled: false
         };
 
+        for line in lines {
+            if subed.prelines.is_empty() {
+                subed.prelines.push(Line::init_with_line(line));
+            } else {
+                subed.postlines.push(Line::init_with_line(line));
+            }
+        }
+        if subed.prelines.is_empty() { subed.prelines.push(Line::init()); }
+
+        subed.postlines.reverse();
+        subed
+    }
+
+    pub fn with_bytes(bytes: &[u8], backing: Backing) -> SubEditor {
+        let (format, lines) = Format::split(bytes);
+        if bytes.len() < LARGE { return SubEditor::with_lines(lines.into_iter(), format); }
+
+        let text = lines.join("\n");
+        let store: Box<dyn Store> = match backing {
+            Backing::Rope => Box::new(rope::Rope::new(&text)),
+            Backing::PieceTable => Box::new(piece::PieceTable::new(text))
+        };
+        let mut subed = SubEditor::with_lines(store.lines(0, 1).into_iter(), format);
+        subed.tail = 1;
+        subed.store = Some(store);
+        subed
+    }
+
+    // Reads the file at `path`, the cases where there is no text file to edit are told apart
+    // so that the caller can deal with them. Other errors (eg. a failing disk) are returned.
+    pub fn open(path: &str, backing: Backing) -> Result<Opened, io::Error> {
+
         let path = Path::new(path);
+        if path.is_dir() { return Ok(Opened::Directory); }
 
-        match read_lines(&path) {
-            Ok(lines) => { 
-                let mut first_line = true;
-                for line in lines {
-                    if first_line {
-                        subed.prelines.push(Line::init_with_line(line?)); 
-                        first_line = false;
-                    } else {
-                        subed.postlines.push(Line::init_with_line(line?));
-                    }
-                }        
-            },
-            Err(_) => { 
-                std::fs::create_dir_all(path)?;
-                subed.prelines.push(Line::init());
-            }
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Opened::New),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(Opened::Denied),
+            Err(e) => return Err(e)
         };
+        if file.metadata()?.len() >= HUGE {
+            // reading it whole would take as much memory as the file, and more to show it
+            let start = first_bytes(&file)?;
+            if format::is_utf16(&start) { return Ok(Opened::TooLarge("UTF-16")); }
+            if format::is_binary(&start) { return Ok(Opened::TooLarge("binary")); }
+            let mut subed = SubEditor::map(&file)?;
+            subed.readonly = !atomic::writable(path);
+            return Ok(Opened::File(subed));
+        }
+        let bytes = read_bytes(file)?;
+        if format::is_binary(&bytes) { return Ok(Opened::Binary(bytes)); }
 
-        subed.postlines.reverse();
+        let mut subed = SubEditor::with_bytes(&bytes, backing);
+        subed.readonly = !atomic::writable(path);
+        // a missing or outdated history just means starting a fresh one
+        if let Ok(history) = History::load(&history::undo_path(path), subed.content_hash()) {
+            subed.history = history;
+            subed.saved = subed.history_position().0;
+        }
+        Ok(Opened::File(subed))
+    }
+
+    // Opens a huge file without reading it, only the first line is read into the buffer. Its
+    // history is not kept, hashing the whole file would take as long as reading it.
+    fn map(file: &File) -> Result<SubEditor, io::Error> {
+        let store = MappedLines::new(Mapped::open(file)?);
+        let mut subed = SubEditor::with_lines(store.lines(0, 1).into_iter(), store.format());
+        subed.tail = 1;
+        subed.store = Some(Box::new(store));
+        subed.mapped = true;
         Ok(subed)
     }
 
+    // Reads the whole buffer from a stream (eg. stdin) instead of a file
+    pub fn from_reader<R: Read>(reader: R, backing: Backing) -> Result<SubEditor, io::Error> {
+        Ok(SubEditor::with_bytes(&read_bytes(reader)?, backing))
+    }
+
     pub fn curr_line_num(&self) -> usize {
-        self.prelines.len() - 1 as usize
+        self.head + self.prelines.len() - 1
     }
 
     pub fn num_lines(&self) -> usize {
-        self.prelines.len() + self.postlines.len()
+        self.window().1 + self.store.as_ref().map_or(0, |store| store.num_lines() - self.tail)
     }
 
-    pub fn curr_line(&self) -> String {
-        let curr_line = self.curr_line_num();
-        self.prelines[curr_line].show()
+    fn curr(&self) -> &Line {
+        self.prelines.last().unwrap()
+    }
+
+    fn curr_mut(&mut self) -> &mut Line {
+        self.prelines.last_mut().unwrap()
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.mapped
+    }
+
+    // How far the lines of a huge file have been indexed in percent, None when they all are
+    pub fn indexing(&self) -> Option<usize> {
+        self.store.as_ref().and_then(|store| store.progress())
+    }
+
+    // Takes in the lines indexed since the last call, returns true if there were any
+    pub fn poll_index(&mut self) -> bool {
+        self.store.as_mut().is_some_and(|store| store.poll())
+    }
+
+    // The lines in prelines and postlines, as (first, past the last)
+    fn window(&self) -> (usize, usize) {
+        (self.head, self.head + self.prelines.len() + self.postlines.len())
+    }
+
+    // The text of line n, taken from the store if needed
+    pub fn line_text(&self, n: usize) -> Option<String> {
+        match self.get_line(n) {
+            Some(line) => Some(line.show()),
+            None => self.text_lines(n, n + 1).pop()
+        }
+    }
+
+    // Lines `from` to `to` of the buffer as text, fewer past the last line
+    fn text_lines(&self, from: usize, to: usize) -> Vec<String> {
+        let to = to.min(self.num_lines());
+        let (start, end) = self.window();
+        let mut lines = match &self.store {
+            Some(store) if from < start => store.lines(from, to.min(start)),
+            _ => Vec::new()
+        };
+        lines.extend((from.max(start)..to.min(end)).filter_map(|n| self.get_line(n)).map(|line| line.show()));
+        if let Some(store) = self.store.as_ref().filter(|_| to > end) {
+            let first = from.max(end);
+            lines.extend(store.lines(self.tail + first - end, self.tail + to - end));
+        }
+        lines
+    }
+
+    // Lines `from` to `to` of the store, as they are read into the buffer
+    fn read_lines(&self, from: usize, to: usize) -> Vec<Line> {
+        let store = self.store.as_ref().unwrap();
+        store.lines(from, to).into_iter().map(Line::init_with_line).collect()
     }
 
-    pub fn get_lines(&self) -> Vec<&line::Line> {
-        self.prelines.iter().chain(self.postlines.iter().rev()).collect()
+    // Puts prelines and postlines back into the store, after which they are the same as its lines.
+    // Only the lines that differ are replaced, so that the store still knows which ones are as
+    // they were read.
+    fn flush(&mut self) {
+        if !self.edited { return; }
+        let lines: Vec<String> = self.prelines.iter().chain(self.postlines.iter().rev()).map(|line| line.show()).collect();
+        let (head, tail) = (self.head, self.tail);
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return
+        };
+        let old = store.lines(head, tail);
+        let prefix = lines.iter().zip(&old).take_while(|(a, b)| a == b).count();
+        let suffix = lines[prefix..].iter().rev().zip(old[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+        let (from, to) = (head + prefix, tail - suffix);
+        self.mangled |= (from..to).any(|n| store.raw(n).is_some_and(|raw| std::str::from_utf8(raw).is_err()));
+        store.replace(from, to, &lines[prefix..lines.len() - suffix]);
+        self.tail = head + lines.len();
+        self.edited = false;
+    }
+
+    // Reads the lines from `from` to `to` into the buffer, next to the ones already there
+    fn grow(&mut self, from: usize, to: usize) {
+        if self.store.is_none() { return; }
+        let (start, end) = self.window();
+        if from < start {
+            let mut lines = self.read_lines(from, start);
+            lines.append(&mut self.prelines);
+            self.prelines = lines;
+            self.head = from;
+        }
+        if to > end {
+            let lines = self.read_lines(self.tail, self.tail + to - end);
+            self.tail += lines.len();
+            let mut postlines = lines;
+            postlines.reverse();
+            postlines.append(&mut self.postlines);
+            self.postlines = postlines;
+        }
     }
 
-    pub fn get_post_lines(&self) -> &Vec<Line> {
-        &self.postlines
+    // Drops the lines read so far for lines `from` to `to`, with the cursor at `pos` in them
+    fn reread(&mut self, from: usize, to: usize, (line, col): Pos) {
+        self.flush();
+        let mut lines = self.read_lines(from, to);
+        self.postlines = lines.split_off(line - from + 1);
+        self.postlines.reverse();
+        self.prelines = lines;
+        self.head = from;
+        self.tail = from + self.prelines.len() + self.postlines.len();
+        self.curr_mut().move_to(col);
+    }
+
+    // Makes sure lines `from` to `to` (eg. the ones on screen) are in the buffer. The lines read
+    // before are put back into the store, so that only those around the cursor are kept as lines.
+    pub fn load(&mut self, from: usize, to: usize) {
+        if self.store.is_none() { return; }
+        let to = to.min(self.num_lines());
+        let (start, end) = self.window();
+        let pos = self.pos();
+        let roomy = end - start > 2 * (to - from.min(to)) + 64;
+        if (from < start || to > end || roomy) && from <= pos.0 && pos.0 < to {
+            self.reread(from, to, pos);
+        } else {
+            self.grow(from, to);
+        }
+    }
+
+    // Line n (0 indexed) of the buffer, wherever the cursor is, None if it was not read in
+    pub fn get_line(&self, n: usize) -> Option<&Line> {
+        let n = n.checked_sub(self.head)?;
+        if n < self.prelines.len() {
+            self.prelines.get(n)
+        } else {
+            let from_end = n - self.prelines.len();
+            if from_end < self.postlines.len() {
+                self.postlines.get(self.postlines.len() - 1 - from_end)
+            } else {
+                None
+            }
+        }
     }
 
     pub fn linelen(&self) -> usize {
-        let curr_line = self.curr_line_num();
-        self.prelines[curr_line].len()
+        self.curr().len()
     }
 
     pub fn cursor(&self) -> usize {
-        let curr_line = self.curr_line_num();
-        self.prelines[curr_line].cursor()
+        self.curr().cursor()
     }
 
     pub fn move_left(&mut self) -> bool {
-        let curr_line = self.curr_line_num();
-        self.prelines[curr_line].move_l()
+        self.curr_mut().move_grapheme_l()
     }
 
     pub fn move_right(&mut self) -> bool {
-        let curr_line = self.curr_line_num();
-        self.prelines[curr_line].move_r()
+        self.curr_mut().move_grapheme_r()
     }
 
     pub fn move_down(&mut self) -> bool {
         let old_cursor = self.cursor();
-        if self.postlines.len() > 0 {
+        let next = self.curr_line_num() + 1;
+        if self.postlines.is_empty() { self.grow(next, next + 1); }
+        if !self.postlines.is_empty() {
             self.prelines.push(self.postlines.pop().unwrap());
-            if self.linelen() < old_cursor { self.move_end(); }
-            else if self.cursor() < old_cursor {
-                while self.cursor() < old_cursor { self.move_right(); }
-            } 
-            else if self.cursor() > old_cursor {
-                while self.cursor() > old_cursor { self.move_left(); }
-            } 
+            self.curr_mut().move_to(old_cursor);
+            self.curr_mut().snap();
             true
         } else {
             false
@@ -118,15 +420,12 @@ impl SubEditor {
  
     pub fn move_up(&mut self) -> bool {
         let old_cursor = self.cursor();
+        let curr_line = self.curr_line_num();
+        if self.prelines.len() == 1 && curr_line > 0 { self.grow(curr_line - 1, curr_line); }
         if self.prelines.len() > 1 {
             self.postlines.push(self.prelines.pop().unwrap());
-            if self.linelen() < old_cursor { self.move_end(); }
-            else if self.cursor() < old_cursor {
-                while self.cursor() < old_cursor { self.move_right(); }
-            } 
-            else if self.cursor() > old_cursor {
-                while self.cursor() > old_cursor { self.move_left(); }
-            } 
+            self.curr_mut().move_to(old_cursor);
+            self.curr_mut().snap();
             true
         } else {
             false
@@ -134,135 +433,987 @@ impl SubEditor {
     }
 
     pub fn move_start(&mut self) {
-        let curr_line = self.curr_line_num();
-        self.prelines[curr_line].move_start()
+        self.curr_mut().move_start()
     }
 
     pub fn move_end(&mut self) {
-        let curr_line = self.curr_line_num();
-        self.prelines[curr_line].move_end()
+        self.curr_mut().move_end()
     }
 
     pub fn move_first(&mut self) {
-        while self.prelines.len() > 1 { self.postlines.push(self.prelines.pop().unwrap()); }
-        let curr_line = self.curr_line_num();
-        self.prelines[curr_line].move_start();
+        self.move_to((0, 0));
+    }
+
+    // Moves to the start of line n (0 indexed), stopping at the last line
+    pub fn move_to_line(&mut self, n: usize) {
+        self.move_to((n, 0));
     }
 
     pub fn move_last(&mut self) {
-        while self.postlines.len() > 0 { self.prelines.push(self.postlines.pop().unwrap()); }
-        let curr_line = self.curr_line_num();
-        self.prelines[curr_line].move_start();
+        self.move_to((self.num_lines() - 1, 0));
+    }
+
+    // Current cursor position as (line, column)
+    pub fn pos(&self) -> Pos {
+        (self.curr_line_num(), self.cursor())
+    }
+
+    // Moves the cursor to a position, clamping it to the buffer
+    pub fn move_to(&mut self, (line, col): Pos) {
+        if self.store.is_some() {
+            let line = line.min(self.num_lines() - 1);
+            let (start, end) = self.window();
+            // outside of the window the store gets to the line at once
+            if line < start || line >= end {
+                self.reread(line, line + 1, (line, col));
+                return;
+            }
+        }
+        while self.curr_line_num() > line && self.prelines.len() > 1 {
+            self.postlines.push(self.prelines.pop().unwrap());
+        }
+        while self.curr_line_num() < line {
+            match self.postlines.pop() {
+                Some(nline) => self.prelines.push(nline),
+                None => break
+            }
+        }
+        self.curr_mut().move_to(col);
+    }
+
+    fn record(&mut self, edit: Edit, before: Pos, after: Pos) {
+        self.anchor = None;
+        self.history.record(edit, before, after);
+    }
+
+    // Starts a selection at the cursor, if there is none yet
+    pub fn start_selection(&mut self) {
+        if self.anchor.is_none() { self.anchor = Some(self.pos()); }
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.anchor = None;
+    }
+
+    pub fn select_all(&mut self) {
+        self.move_first();
+        self.anchor = Some(self.pos());
+        self.move_last();
+        self.move_end();
+    }
+
+    // The selected range as (start, end), end excluded
+    pub fn selection(&self) -> Option<(Pos, Pos)> {
+        let anchor = self.anchor?;
+        let pos = self.pos();
+        if anchor == pos { return None; }
+        Some(if anchor < pos { (anchor, pos) } else { (pos, anchor) })
+    }
+
+    // The text between two positions, end excluded, lines joined with newlines
+    pub fn get_range(&self, start: Pos, end: Pos) -> String {
+        let mut text = String::new();
+        for (n, line) in (start.0..).zip(self.text_lines(start.0, end.0 + 1)) {
+            let from = if n == start.0 { start.1 } else { 0 };
+            let to = if n == end.0 { end.1 } else { usize::MAX };
+            text.extend(line.chars().skip(from).take(to.saturating_sub(from)));
+            if n != end.0 { text.push('\n'); }
+        }
+        text
+    }
+
+    // Column ranges of the matches in line n
+    pub fn find_in_line(&self, n: usize, matcher: &Matcher) -> Vec<(usize, usize)> {
+        self.line_text(n).map_or(Vec::new(), |line| matcher.find_all(&line))
+    }
+
+    // Start of the first match at or after `from` going forward, or the last one before it
+    // going backward, wrapping around the ends of the buffer
+    pub fn find(&self, matcher: &Matcher, from: Pos, forward: bool) -> Option<Pos> {
+        let lines = self.num_lines();
+        let mut batch = (0, Vec::new());
+        for i in 0..=lines {
+            let n = if forward { (from.0 + i) % lines } else { (from.0 + lines - i % lines) % lines };
+            if n < batch.0 || n >= batch.0 + batch.1.len() {
+                let start = if forward { n } else { (n + 1).saturating_sub(BATCH) };
+                batch = (start, self.text_lines(start, start + BATCH));
+            }
+            // the line the search starts on is looked at twice, once on each side of `from`
+            let (first, last) = (i == 0, i == lines);
+            let mut starts = matcher.find_all(&batch.1[n - batch.0]).into_iter().map(|(start, _)| start);
+            let found = if forward {
+                starts.find(|&start| (!first || start >= from.1) && (!last || start < from.1))
+            } else {
+                starts.rev().find(|&start| (!first || start < from.1) && (!last || start >= from.1))
+            };
+            if let Some(col) = found { return Some((n, col)); }
+        }
+        None
+    }
+
+    // Replaces the match starting at `start` as one undo step, returns where the new text ends
+    pub fn replace_match(&mut self, matcher: &Matcher, start: Pos, with: &str) -> Option<Pos> {
+        if self.readonly { return None; }
+        let line = self.line_text(start.0)?;
+        let (end, text) = matcher.replacement(&line, start.1, with)?;
+        let before = self.pos();
+
+        self.move_to(start);
+        self.remove_text(end - start.1);
+        self.insert_text(&text);
+        let after = self.pos();
+
+        self.anchor = None;
+        self.history.record_step(vec![
+            Edit::Remove { line: start.0, col: start.1, text: line.chars().skip(start.1).take(end - start.1).collect() },
+            Edit::Insert { line: start.0, col: start.1, text }
+        ], before, after);
+        Some(after)
+    }
+
+    // The next match of a replace going around the buffer, None once it is back at its origin
+    pub fn next_replace(&self, matcher: &Matcher, walk: &mut Replacing) -> Option<Pos> {
+        let found = self.find(matcher, walk.from, true)?;
+        if found < walk.from { walk.wrapped = true; }
+        if walk.wrapped && found >= walk.origin { return None; }
+        Some(found)
+    }
+
+    // Replaces the match at `found` that next_replace gave, or skips it without `with`, and goes
+    // on after it. Returns true if it was replaced.
+    pub fn replace_next(&mut self, matcher: &Matcher, walk: &mut Replacing, found: Pos, with: Option<&str>) -> bool {
+        let linelen = |subed: &SubEditor| subed.line_text(found.0).map_or(0, |line| line.chars().count());
+        let before = linelen(self);
+        let end = with.and_then(|with| self.replace_match(matcher, found, with));
+        walk.from = match end {
+            // an empty match replaced by nothing would be found again
+            Some(end) if end != found => end,
+            _ => (found.0, found.1 + 1)
+        };
+        // the matches not looked at yet on the origin line move along with the text before them
+        if end.is_some() && found.0 == walk.origin.0 && found.1 < walk.origin.1 {
+            walk.origin.1 = (walk.origin.1 + linelen(self)).saturating_sub(before);
+        }
+        end.is_some()
+    }
+
+    // Replaces every match in the buffer as one undo step, returns the number of matches.
+    // Changed lines are rebuilt whole rather than edited match by match.
+    pub fn replace_all(&mut self, matcher: &Matcher, with: &str) -> usize {
+        if self.readonly { return 0; }
+        let before = self.pos();
+        let mut edits = Vec::new();
+        let mut count = 0;
+        if let Some(store) = &mut self.store { store.wait(); }
+
+        for from in (0..self.num_lines()).step_by(BATCH) {
+            for (n, line) in (from..).zip(self.text_lines(from, from + BATCH)) {
+                if let Some((replaced, matches)) = matcher.replace_all(&line, with) {
+                    count += matches;
+                    self.set_line(n, replaced.clone());
+                    edits.push(Edit::Remove { line: n, col: 0, text: line });
+                    edits.push(Edit::Insert { line: n, col: 0, text: replaced });
+                }
+            }
+        }
+        if edits.is_empty() { return 0; }
+
+        self.move_to(before);
+        self.anchor = None;
+        self.history.record_step(edits, before, self.pos());
+        count
+    }
+
+    fn set_line(&mut self, n: usize, text: String) {
+        self.move_to((n, 0));
+        self.edited = true;
+        *self.curr_mut() = Line::init_with_line(text);
+    }
+
+    // Removes the text between two positions as one undo step, returns the removed text
+    pub fn remove_range(&mut self, start: Pos, end: Pos) -> String {
+        if self.readonly { return String::new(); }
+        let before = self.pos();
+        let text = self.get_range(start, end);
+        self.move_to(start);
+        self.remove_text(text.chars().count());
+        let start = self.pos();
+
+        self.anchor = None;
+        self.history.record_step(vec![Edit::Remove { line: start.0, col: start.1, text: text.clone() }], before, start);
+        text
+    }
+
+    // Inserts text that may span several lines at the cursor, as one undo step
+    pub fn insert_str(&mut self, text: &str) {
+        if text.is_empty() || self.readonly { return; }
+        let before = self.pos();
+        self.insert_text(text);
+
+        self.anchor = None;
+        self.history.record_step(vec![Edit::Insert { line: before.0, col: before.1, text: text.to_string() }], before, self.pos());
+    }
+
+    // Replaces the whole buffer with `text` as one undo step, keeping the cursor where it can
+    pub fn set_text(&mut self, text: &str) {
+        if self.readonly { return; }
+        let before = self.pos();
+        let last = self.num_lines() - 1;
+        let old = self.get_range((0, 0), (last, self.line_text(last).map_or(0, |line| line.chars().count())));
+        self.move_to((0, 0));
+        self.remove_text(old.chars().count());
+        self.insert_text(text);
+        self.move_to(before);
+
+        self.anchor = None;
+        self.history.record_step(vec![
+            Edit::Remove { line: 0, col: 0, text: old },
+            Edit::Insert { line: 0, col: 0, text: text.to_string() }
+        ], before, self.pos());
     }
 
     pub fn backspace(&mut self) -> DEL {
+        if self.readonly { return DEL::No; }
+        let before = self.pos();
+        let text = self.curr().prev_grapheme().unwrap_or_else(|| "\n".to_string());
+        let res = self.backspace_char();
+        if let DEL::No = res { return res; }
+
+        let after = self.pos();
+        self.record(Edit::Remove { line: after.0, col: after.1, text }, before, after);
+        res
+    }
+
+    pub fn delete(&mut self) -> DEL {
+        if self.readonly { return DEL::No; }
+        let before = self.pos();
+        let text = self.curr().next_grapheme().unwrap_or_else(|| "\n".to_string());
+        let res = self.delete_char();
+        if let DEL::No = res { return res; }
+
+        self.record(Edit::Remove { line: before.0, col: before.1, text }, before, self.pos());
+        res
+    }
+
+    fn backspace_char(&mut self) -> DEL {
+        self.edited = true;
         let curr_line = self.curr_line_num();
-        if self.prelines[curr_line].backspace() {
+        if self.prelines.len() == 1 && curr_line > 0 { self.grow(curr_line - 1, curr_line); }
+        if self.curr_mut().backspace() {
             DEL::Yes
         } else if self.prelines.len() > 1 {
-            let nline = self.prelines[curr_line].show();
+            let nline = self.curr().show();
             self.prelines.pop();
-            let curr_line = self.curr_line_num();
-            self.prelines[curr_line].move_end();
+            self.curr_mut().move_end();
             let linelen = self.linelen();
-            for ch in nline.chars() { self.prelines[curr_line].insert(ch) }
-            for _ in (linelen..self.linelen()) { self.move_left(); }
-            DEL::NewLine(linelen)
+            self.curr_mut().insert_str(&nline);
+            self.curr_mut().move_to(linelen);
+            DEL::NewLine
         } else {
             DEL::No
         }
     }
 
-    pub fn delete(&mut self) -> DEL {
-        let curr_line = self.curr_line_num();
-        if self.prelines[curr_line].delete() {
+    fn delete_char(&mut self) -> DEL {
+        self.edited = true;
+        let next = self.curr_line_num() + 1;
+        if self.postlines.is_empty() { self.grow(next, next + 1); }
+        if self.curr_mut().delete() {
             DEL::Yes
-        } else if self.postlines.len() > 0 {
+        } else if !self.postlines.is_empty() {
             let nline = self.postlines.last().unwrap().show();
-            self.prelines[curr_line].move_end();
+            self.curr_mut().move_end();
             self.postlines.pop();
             let linelen = self.linelen();
-            for ch in nline.chars() { self.prelines[curr_line].insert(ch) }
-            for _ in (linelen..self.linelen()) { self.move_left(); }
-            DEL::NewLine(linelen)
+            self.curr_mut().insert_str(&nline);
+            self.curr_mut().move_to(linelen);
+            DEL::NewLine
         } else {
             DEL::No
         }
     }
 
+    #[allow(dead_code)]
     pub fn remove_empty_line(&mut self) -> bool {
+        self.edited = true;
         if self.linelen() == 0 && self.num_lines() > 1 {
             self.prelines.pop();
-            if self.prelines.len() == 0 { 
+            if self.prelines.is_empty() { 
                 self.prelines.push(self.postlines.pop().unwrap()); 
-                let curr_line = self.curr_line_num();
-                self.prelines[curr_line].move_start();
+                self.curr_mut().move_start();
                 return false;
             } else { 
-                let curr_line = self.curr_line_num();
-                self.prelines[curr_line].move_start();        
+                self.curr_mut().move_start();
                 return true;
             }
-        } 
-        return false;
+        }
+        false
     }
 
     pub fn insert(&mut self, newchar: char) {
-        let curr_line = self.curr_line_num();
-        self.prelines[curr_line].insert(newchar);
+        if self.readonly { return; }
+        let before = self.pos();
+        self.insert_char(newchar);
+        self.record(Edit::Insert { line: before.0, col: before.1, text: newchar.to_string() }, before, self.pos());
     }
 
     pub fn insert_newline(&mut self) -> String {
-        let mut newline = String::new();
-        let curr_line = self.curr_line_num();
-        let cline = &mut self.prelines[curr_line];
-        for i in cline.get_post()+1..cline.get_len() {
-            newline.push(cline.get_text(i)); 
-        }
+        if self.readonly { return String::new(); }
+        let before = self.pos();
+        let prevline = self.split_line();
+        self.record(Edit::Insert { line: before.0, col: before.1, text: "\n".to_string() }, before, self.pos());
+        prevline
+    }
 
-        cline.set_post(cline.get_len()-1);
-        let prevline = cline.show();
-        self.prelines.push(Line::init_with_line(newline));
+    fn insert_char(&mut self, newchar: char) {
+        self.edited = true;
+        self.curr_mut().insert(newchar);
+    }
 
-        prevline
+    // Inserts text that may contain newlines at the cursor, leaving the cursor after it.
+    // The lines in between are built directly instead of typing the text char by char.
+    fn insert_text(&mut self, text: &str) {
+        let mut segments: Vec<&str> = text.split('\n').collect();
+        let last = segments.pop().unwrap();
+        self.edited = true;
+        if segments.is_empty() {
+            self.curr_mut().insert_str(last);
+            return;
+        }
+
+        self.curr_mut().insert_str(segments[0]);
+        let tail = self.curr_mut().split_off();
+        for segment in segments.iter().skip(1) {
+            self.prelines.push(Line::init_with_line(segment.to_string()));
+        }
+        let mut last_line = Line::init_with_line(format!("{}{}", last, tail));
+        last_line.move_to(last.chars().count());
+        self.prelines.push(last_line);
     }
 
-    pub fn show_curr_line(&mut self) -> String {
-        self.prelines[self.curr_line_num()].show()
+    // Removes n chars after the cursor, newlines included
+    fn remove_text(&mut self, mut n: usize) {
+        self.edited = true;
+        while n > 0 {
+            let after = self.linelen() - self.cursor();
+            if n <= after {
+                self.curr_mut().delete_n(n);
+                return;
+            }
+            self.curr_mut().delete_n(after);
+            if let DEL::No = self.delete_char() { return; }
+            n -= after + 1;
+        }
     }
 
-    pub fn show_curr_post_line(&mut self) -> String {
-        let mut post_line = String::new();
-        let cline = &self.prelines[self.curr_line_num()];
-        for i in cline.get_post()+1..cline.get_len() { 
-            post_line.push(self.prelines[self.curr_line_num()].get_text(i)); 
+    // Applies the moves of a walk through the history tree, returns false if there were none
+    fn apply_moves(&mut self, moves: Vec<Move>) -> bool {
+        self.anchor = None;
+        let moved = !moves.is_empty();
+        for mv in moves {
+            match mv {
+                Move::Undo(step) => {
+                    for edit in step.edits.iter().rev() {
+                        match edit {
+                            Edit::Insert { line, col, text } => {
+                                self.move_to((*line, *col));
+                                self.remove_text(text.chars().count());
+                            }
+                            Edit::Remove { line, col, text } => {
+                                self.move_to((*line, *col));
+                                self.insert_text(text);
+                            }
+                        }
+                    }
+                    self.move_to(step.before);
+                }
+                Move::Redo(step) => {
+                    for edit in step.edits.iter() {
+                        match edit {
+                            Edit::Insert { line, col, text } => {
+                                self.move_to((*line, *col));
+                                self.insert_text(text);
+                            }
+                            Edit::Remove { line, col, text } => {
+                                self.move_to((*line, *col));
+                                self.remove_text(text.chars().count());
+                            }
+                        }
+                    }
+                    self.move_to(step.after);
+                }
+            }
         }
+        moved
+    }
+
+    // Reverts the last group of edits, returns false if there was nothing to undo
+    pub fn undo(&mut self) -> bool {
+        if self.readonly { return false; }
+        let moves = self.history.undo();
+        self.apply_moves(moves)
+    }
+
+    pub fn redo(&mut self) -> bool {
+        if self.readonly { return false; }
+        let moves = self.history.redo();
+        self.apply_moves(moves)
+    }
+
+    // Goes back (negative) or forward through every state the buffer has been in, across branches
+    pub fn time_travel(&mut self, steps: isize) -> bool {
+        if self.readonly { return false; }
+        let moves = if steps < 0 {
+            self.history.earlier(steps.unsigned_abs())
+        } else {
+            self.history.later(steps as usize)
+        };
+        self.apply_moves(moves)
+    }
+
+    pub fn time_travel_secs(&mut self, secs: i64) -> bool {
+        if self.readonly { return false; }
+        let moves = if secs < 0 {
+            self.history.earlier_by_time(secs.unsigned_abs())
+        } else {
+            self.history.later_by_time(secs as u64)
+        };
+        self.apply_moves(moves)
+    }
+
+    // Switches the branch that redo follows, returns (branch, number of branches)
+    pub fn next_branch(&mut self) -> (usize, usize) {
+        self.history.next_branch()
+    }
+
+    // (current state, number of states, seconds since the current state was made)
+    pub fn history_position(&self) -> (usize, usize, u64) {
+        self.history.position()
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    pub fn set_readonly(&mut self, readonly: bool) {
+        self.readonly = readonly;
+    }
+
+    // If the buffer differs from the last saved state, undoing back to it counts as unmodified
+    pub fn is_modified(&self) -> bool {
+        self.history_position().0 != self.saved || self.format != self.saved_format
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    // The first line with characters that `encoding` cannot hold
+    pub fn unencodable_line(&self, encoding: Encoding) -> Option<usize> {
+        (0..self.num_lines()).step_by(BATCH).find_map(|from| {
+            let lines = self.text_lines(from, from + BATCH);
+            lines.iter().position(|line| encoding.encode(line).is_none()).map(|i| from + i)
+        })
+    }
+
+    // Encoding, line endings, final newline and BOM to save with, which makes the buffer modified
+    pub fn set_format(&mut self, format: Format) {
+        if self.readonly { return; }
+        self.format = format;
+    }
+
+    // Grows with every change to the buffer, to tell if it changed since some earlier point
+    pub fn changes(&self) -> u64 {
+        self.history.changes()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.text_lines(0, self.num_lines())
+    }
+
+    fn content_hash(&self) -> u64 {
+        history::hash_lines(self.lines().iter().map(|line| line.as_str()))
+    }
+
+    fn split_line(&mut self) -> String {
+        self.edited = true;
+        let newline = self.curr_mut().split_off();
+        let prevline = self.curr().show();
+        self.prelines.push(Line::init_with_line(newline));
+
+        prevline
+    }
 
-        post_line
+    #[allow(dead_code)]
+    pub fn show_curr_line(&mut self) -> String {
+        self.curr().show()
     }
 
+    #[allow(dead_code)]
     pub fn show(&self) -> String { // use to see internal state of editor
         let mut ed_state = String::new();
         ed_state.push_str(
-            &format!("line: {}, cursor: {}", self.curr_line_num() + 1 as usize, self.cursor() + 1 as usize)
+            &format!("line: {}, cursor: {}", self.curr_line_num() + 1, self.cursor() + 1)
         );
         ed_state.push('\n');
-        for (i,cline) in self.get_lines().iter().enumerate() {
-            ed_state.push_str(&format!("{} | {}", i+1, cline.show()));
+        for (i,cline) in self.lines().iter().enumerate() {
+            ed_state.push_str(&format!("{} | {}", i+1, cline));
         }
 
         ed_state.to_string()
     }
 
-    pub fn save(&self, path: &str) -> Result<(), std::io::Error> {
+    // Returns the number of lines and bytes written, with `backup` the old file is kept as `path~`
+    pub fn save(&mut self, path: &str, backup: bool) -> Result<(usize, usize), std::io::Error> {
 
         let path = Path::new(path);
-        let mut file = File::create(path)?;
+        self.flush();
+        if self.mangled {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "an edited line was not valid UTF-8, saving would lose its bytes"));
+        }
+        if let Some(store) = &mut self.store { store.wait(); }
+        let format = self.format;
+        let newline = format.newline();
+        let last = self.num_lines() - 1;
+        let mut bytes = 0;
+        // a mapped file must not change under the map, which writing over it in place would do
+        let write = if self.mapped { atomic::write_by_rename } else { atomic::write };
+        write(path, backup, &mut |out: &mut dyn Write| {
+            bytes += write_encoded(out, format.encoding, format.bom(), 0)?;
+            let mut n = 0;
+            while n <= last {
+                // the lines of a huge file that were not edited are copied as they are
+                let count = match self.run(n, format) {
+                    Ok((raw, count)) => {
+                        out.write_all(raw)?;
+                        bytes += raw.len();
+                        n += count;
+                        continue;
+                    }
+                    Err(count) => count.min(BATCH)
+                };
+                for line in self.text_lines(n, n + count) {
+                    match self.raw(n, format) {
+                        Some(raw) => {
+                            out.write_all(raw)?;
+                            bytes += raw.len();
+                        }
+                        None => bytes += write_encoded(out, format.encoding, &line, n)?
+                    }
+                    if n < last || format.final_newline {
+                        bytes += write_encoded(out, format.encoding, newline, n)?;
+                    }
+                    n += 1;
+                }
+            }
+            Ok(())
+        })?;
+
+        // the file itself is safe at this point, losing its history is not worth failing the save
+        if self.history_position().1 > 0 && !self.mapped {
+            self.history.save(&history::undo_path(path), path, self.content_hash()).ok();
+        }
+        self.history.seal();
+        self.saved = self.history_position().0;
+        self.saved_format = self.format;
+
+        Ok((self.num_lines(), bytes))
+    }
 
-        for cline in self.get_lines() {
-            cline.save(&mut file)?;
+    // Lines from n on that can be copied from the file as they are, as the bytes and the number
+    // of lines, or else the number of lines that cannot. Only after a flush, which leaves the
+    // store with the same lines as the buffer.
+    fn run(&self, n: usize, format: Format) -> Result<(&[u8], usize), usize> {
+        match &self.store {
+            Some(store) if format.encoding == Encoding::Utf8 => store.run(n, format.newline()),
+            _ => Err(self.num_lines() - n)
         }
+    }
+
+    // Line n as it is in the file, if it can be copied from it
+    fn raw(&self, n: usize, format: Format) -> Option<&[u8]> {
+        self.store.as_ref().filter(|_| format.encoding == Encoding::Utf8)?.raw(n)
+    }
+
+    // Keeps the buffer in the swap file `path`, which is as private as the file `like`
+    pub fn write_swap(&self, path: &Path, like: &Path) -> Result<(), std::io::Error> {
+        atomic::write_like(path, like, |out| {
+            swap::write_header(out)?;
+            for from in (0..self.num_lines()).step_by(BATCH) {
+                for line in self.text_lines(from, from + BATCH) {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Buffer for SubEditor {
+
+    fn num_lines(&self) -> usize {
+        SubEditor::num_lines(self)
+    }
 
-        Ok(())
+    fn line(&self, n: usize) -> Option<String> {
+        self.line_text(n)
     }
-}    
+
+    fn get_range(&self, start: Pos, end: Pos) -> String {
+        SubEditor::get_range(self, start, end)
+    }
+
+    fn insert_at(&mut self, pos: Pos, text: &str) {
+        self.move_to(pos);
+        self.insert_str(text);
+    }
+
+    fn remove_range(&mut self, start: Pos, end: Pos) -> String {
+        SubEditor::remove_range(self, start, end)
+    }
+}
+
+impl Buffer for piece::PieceTable {
+
+    fn num_lines(&self) -> usize {
+        piece::PieceTable::num_lines(self)
+    }
+
+    fn line(&self, n: usize) -> Option<String> {
+        piece::PieceTable::line(self, n)
+    }
+
+    fn get_range(&self, start: Pos, end: Pos) -> String {
+        piece::PieceTable::get_range(self, start, end)
+    }
+
+    fn insert_at(&mut self, pos: Pos, text: &str) {
+        self.insert(pos, text);
+    }
+
+    fn remove_range(&mut self, start: Pos, end: Pos) -> String {
+        self.remove(start, end)
+    }
+}
+
+impl Buffer for rope::Rope {
+
+    fn num_lines(&self) -> usize {
+        rope::Rope::num_lines(self)
+    }
+
+    fn line(&self, n: usize) -> Option<String> {
+        rope::Rope::line(self, n)
+    }
+
+    fn get_range(&self, start: Pos, end: Pos) -> String {
+        let (start, end) = (self.pos_to_char(start), self.pos_to_char(end));
+        self.slice(self.char_to_byte(start), self.char_to_byte(end))
+    }
+
+    fn insert_at(&mut self, pos: Pos, text: &str) {
+        self.insert(self.pos_to_char(pos), text);
+    }
+
+    fn remove_range(&mut self, start: Pos, end: Pos) -> String {
+        self.remove(self.pos_to_char(start), self.pos_to_char(end))
+    }
+}
+
+// Lines `from` to `to` of a buffer, as one range rather than going to every line on its own
+fn buffer_lines<B: Buffer>(buffer: &B, from: usize, to: usize) -> Vec<String> {
+    let to = to.min(buffer.num_lines());
+    if from >= to { return Vec::new(); }
+    let end = if to < buffer.num_lines() { (to, 0) } else { (to - 1, usize::MAX) };
+    let mut lines: Vec<String> = buffer.get_range((from, 0), end).split('\n').map(|line| line.to_string()).collect();
+    // the newline of the last line is part of the range, unless it is the last line of all
+    if to < buffer.num_lines() { lines.pop(); }
+    lines
+}
+
+// Replaces lines `from` to `to` of a buffer, `from` can be the line after the last one
+fn replace_buffer_lines<B: Buffer>(buffer: &mut B, from: usize, to: usize, lines: &[String]) {
+    let last = buffer.num_lines() - 1;
+    if to <= last {
+        buffer.remove_range((from, 0), (to, 0));
+        if !lines.is_empty() { buffer.insert_at((from, 0), &(lines.join("\n") + "\n")); }
+    } else if lines.is_empty() {
+        // the last lines go along with the newline before them
+        buffer.remove_range((from - 1, usize::MAX), (last, usize::MAX));
+    } else if from <= last {
+        buffer.remove_range((from, 0), (last, usize::MAX));
+        buffer.insert_at((from, 0), &lines.join("\n"));
+    } else {
+        buffer.insert_at((last, usize::MAX), &("\n".to_string() + &lines.join("\n")));
+    }
+}
+
+impl Store for rope::Rope {
+
+    fn num_lines(&self) -> usize {
+        rope::Rope::num_lines(self)
+    }
+
+    fn lines(&self, from: usize, to: usize) -> Vec<String> {
+        buffer_lines(self, from, to)
+    }
+
+    fn replace(&mut self, from: usize, to: usize, lines: &[String]) {
+        replace_buffer_lines(self, from, to, lines);
+    }
+}
+
+impl Store for piece::PieceTable {
+
+    fn num_lines(&self) -> usize {
+        piece::PieceTable::num_lines(self)
+    }
+
+    fn lines(&self, from: usize, to: usize) -> Vec<String> {
+        buffer_lines(self, from, to)
+    }
+
+    fn replace(&mut self, from: usize, to: usize, lines: &[String]) {
+        replace_buffer_lines(self, from, to, lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn typing_after_save_is_modified() {
+        let path = std::env::temp_dir().join(format!("med-test-{}-save.txt", std::process::id()));
+        let mut subed = SubEditor::with_bytes(b"hello", Backing::Rope);
+        subed.insert('a');
+        subed.save(path.to_str().unwrap(), false).unwrap();
+        assert!(!subed.is_modified());
+
+        subed.insert('b');
+        assert!(subed.is_modified());
+        assert_eq!(subed.lines(), vec!["abhello"]);
+        subed.undo();
+        assert!(!subed.is_modified());
+        assert_eq!(subed.lines(), vec!["ahello"]);
+
+        fs::remove_file(&path).ok();
+        fs::remove_file(history::undo_path(&path)).ok();
+    }
+
+    // What saving the file `bytes` right after opening it writes
+    fn resaved(bytes: &[u8], name: &str) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("med-test-{}-{}", std::process::id(), name));
+        SubEditor::with_bytes(bytes, Backing::Rope).save(path.to_str().unwrap(), false).unwrap();
+        let saved = fs::read(&path).unwrap();
+        fs::remove_file(&path).ok();
+        saved
+    }
+
+    #[test]
+    fn saving_keeps_the_file_as_it_was() {
+        for (i, text) in [&b"a\r\nb\nc\r\n"[..], b"a\nb\r\n", b"a\r\nb", b"\xef\xbb\xbfa\r\n", b"caf\xe9", b""].iter().enumerate() {
+            assert_eq!(resaved(text, &format!("resave-{}", i)), *text);
+        }
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("med-test-{}-{}", std::process::id(), name))
+    }
+
+    // A file that is mapped however small it is
+    fn mapped(bytes: &[u8], name: &str) -> (SubEditor, std::path::PathBuf) {
+        let path = temp_path(name);
+        fs::write(&path, bytes).unwrap();
+        let mut subed = SubEditor::map(&File::open(&path).unwrap()).unwrap();
+        subed.store.as_mut().unwrap().wait();
+        (subed, path)
+    }
+
+    #[test]
+    fn mapped_files_only_keep_the_edited_lines() {
+        let text: String = (0..1000).map(|n| format!("line {}\n", n)).collect();
+        let (mut subed, path) = mapped(text.as_bytes(), "mapped-edits");
+        subed.move_to((10, 0));
+        subed.insert('a');
+        subed.move_to((900, 4));
+        subed.insert_newline();
+        subed.move_to((500, 0));
+        subed.delete();
+        // the lines in between were not read in on the way
+        assert!(subed.prelines.len() + subed.postlines.len() < 4);
+        assert_eq!(subed.num_lines(), 1001);
+
+        let saved = temp_path("mapped-edits-saved");
+        subed.save(saved.to_str().unwrap(), false).unwrap();
+        let expected = text.replacen("line 10\n", "aline 10\n", 1).replacen("line 900\n", "line\n 900\n", 1).replacen("line 500\n", "ine 500\n", 1);
+        assert_eq!(fs::read_to_string(&saved).unwrap(), expected);
+        subed.undo();
+        subed.undo();
+        subed.undo();
+        subed.save(saved.to_str().unwrap(), false).unwrap();
+        assert_eq!(fs::read_to_string(&saved).unwrap(), text);
+
+        fs::remove_file(&path).ok();
+        fs::remove_file(&saved).ok();
+    }
+
+    #[test]
+    fn mapped_files_keep_bytes_that_are_not_utf8() {
+        let text = b"caf\xe9\r\nok\n\xff\r\nlast \xfe";
+        let (mut subed, path) = mapped(text, "mapped-lossy");
+        let saved = temp_path("mapped-lossy-saved");
+        subed.move_to((1, 2));
+        subed.insert('!');
+        subed.save(saved.to_str().unwrap(), false).unwrap();
+        // the edited line gets the line ending of the file
+        assert_eq!(fs::read(&saved).unwrap(), b"caf\xe9\r\nok!\r\n\xff\r\nlast \xfe");
+
+        // written line by line with other line endings, the bytes are still kept
+        subed.set_format(Format { crlf: false, ..subed.format() });
+        subed.save(saved.to_str().unwrap(), false).unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), b"caf\xe9\nok!\n\xff\nlast \xfe");
+
+        // but an edited line only has its replacement chars left
+        subed.move_to((0, 0));
+        subed.insert('!');
+        assert_eq!(subed.line_text(0).unwrap(), "!caf\u{fffd}");
+        assert!(subed.save(saved.to_str().unwrap(), false).is_err());
+        assert_eq!(fs::read(&saved).unwrap(), b"caf\xe9\nok!\n\xff\nlast \xfe");
+
+        fs::remove_file(&path).ok();
+        fs::remove_file(&saved).ok();
+    }
+
+    // Makes the same random edits to a buffer with a store and to one without, which have to
+    // end up with the same lines and save the same file
+    fn edits_like_plain_lines(mut stored: SubEditor, text: &str, name: &str) {
+        let (format, lines) = Format::split(text.as_bytes());
+        let mut plain = SubEditor::with_lines(lines.into_iter(), format);
+        assert!(stored.store.is_some() && plain.store.is_none());
+
+        let mut seed = 7u64;
+        let mut random = |below: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % below as u64) as usize
+        };
+        for step in 0..2000 {
+            // mostly near the cursor, sometimes far away or at the ends
+            let line = match random(4) {
+                0 => random(plain.num_lines()),
+                1 => [0, plain.num_lines() - 1][random(2)],
+                _ => (plain.pos().0 + random(5)).saturating_sub(2)
+            };
+            let pos = (line, random(12));
+            let op = random(9);
+            for subed in [&mut stored, &mut plain] {
+                subed.move_to(pos);
+                match op {
+                    0 => subed.insert('x'),
+                    1 => { subed.insert_newline(); }
+                    2 => { subed.backspace(); }
+                    3 => { subed.delete(); }
+                    4 => subed.insert_str("a\nb\nc"),
+                    5 => { subed.remove_range(pos, (pos.0 + 3, 2)); }
+                    6 => { subed.undo(); }
+                    7 => subed.load(pos.0.saturating_sub(10), pos.0 + 10),
+                    _ => { subed.redo(); }
+                }
+            }
+            assert_eq!(stored.pos(), plain.pos(), "step {}", step);
+            assert_eq!(stored.num_lines(), plain.num_lines(), "step {}", step);
+        }
+        assert_eq!(stored.lines(), plain.lines());
+        assert!(stored.prelines.len() + stored.postlines.len() < 100);
+
+        let (a, b) = (temp_path(&format!("{}-stored", name)), temp_path(&format!("{}-plain", name)));
+        stored.save(a.to_str().unwrap(), false).unwrap();
+        plain.save(b.to_str().unwrap(), false).unwrap();
+        assert_eq!(fs::read(&a).unwrap(), fs::read(&b).unwrap());
+        for path in [a, b] {
+            fs::remove_file(&path).ok();
+            fs::remove_file(history::undo_path(&path)).ok();
+        }
+    }
+
+    #[test]
+    fn rope_store_edits_like_plain_lines() {
+        let text: String = (0..80_000).map(|n| format!("line {} é\n", n)).collect();
+        edits_like_plain_lines(SubEditor::with_bytes(text.as_bytes(), Backing::Rope), &text, "rope");
+    }
+
+    #[test]
+    fn piece_table_store_edits_like_plain_lines() {
+        let text: String = (0..80_000).map(|n| format!("line {} é\n", n)).collect();
+        edits_like_plain_lines(SubEditor::with_bytes(text.as_bytes(), Backing::PieceTable), &text, "piece");
+    }
+
+    #[test]
+    fn mapped_store_edits_like_plain_lines() {
+        let text: String = (0..5_000).map(|n| format!("line {} é\r\n", n)).collect();
+        let (subed, path) = mapped(text.as_bytes(), "mapped-random");
+        edits_like_plain_lines(subed, &text, "mapped");
+        fs::remove_file(&path).ok();
+    }
+
+    // What replacing every match going around from `origin` leaves, as confirming each one does
+    fn replaced_around(text: &str, origin: Pos, pattern: &str, with: &str) -> (Vec<String>, usize) {
+        let mut subed = SubEditor::with_bytes(text.as_bytes(), Backing::Rope);
+        let matcher = search::Query { pattern: pattern.to_string(), ..Default::default() }.compile().unwrap();
+        let (mut walk, mut count) = (Replacing::new(origin), 0);
+        while let Some(found) = subed.next_replace(&matcher, &mut walk) {
+            if subed.replace_next(&matcher, &mut walk, found, Some(with)) { count += 1; }
+        }
+        (subed.lines(), count)
+    }
+
+    #[test]
+    fn confirmed_replace_goes_around_once() {
+        // the matches before the origin on its line move when the ones in front of them grow
+        assert_eq!(replaced_around("aaaaa", (0, 4), "a", "bbb"), (vec!["b".repeat(15)], 5));
+        assert_eq!(replaced_around("aaaaa", (0, 2), "aa", ""), (vec!["a".to_string()], 2));
+        assert_eq!(replaced_around("xa\nab\nax", (1, 1), "a", "aa"), (vec!["xaa".to_string(), "aab".to_string(), "aax".to_string()], 3));
+        // a replacement that matches again is not looked at a second time
+        assert_eq!(replaced_around("a a", (0, 0), "a", "aa"), (vec!["aa aa".to_string()], 2));
+    }
+
+    #[test]
+    fn huge_files_that_cannot_be_mapped_are_not_read() {
+        for (start, kind) in [(&b"\0\x01\x02"[..], "binary"), (&b"\xff\xfea\0"[..], "UTF-16")] {
+            let path = temp_path(&format!("huge-{}", kind));
+            // a sparse file, so that the test does not write 64 MB
+            let file = File::create(&path).unwrap();
+            (&file).write_all(start).unwrap();
+            file.set_len(HUGE).unwrap();
+            match SubEditor::open(path.to_str().unwrap(), Backing::Rope).unwrap() {
+                Opened::TooLarge(found) => assert_eq!(found, kind),
+                _ => panic!("{} file opened", kind)
+            }
+            fs::remove_file(&path).ok();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn history_is_as_private_as_the_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("med-test-{}-private.txt", std::process::id()));
+        fs::write(&path, "secret").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut subed = match SubEditor::open(path.to_str().unwrap(), Backing::Rope).unwrap() {
+            Opened::File(subed) => subed,
+            _ => panic!("not opened as a file")
+        };
+        subed.insert('x');
+        subed.save(path.to_str().unwrap(), false).unwrap();
+        let mode = fs::metadata(history::undo_path(&path)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_file(&path).ok();
+        fs::remove_file(history::undo_path(&path)).ok();
+    }
+}
@@ -0,0 +1,270 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Same limit the kernel puts on nested symlinks
+static MAX_LINKS: usize = 40;
+
+// The file that `path` points to, through any number of symlinks, even if it does not exist yet
+fn resolve(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+    for _ in 0..MAX_LINKS {
+        match fs::read_link(&path) {
+            // relative links are relative to the directory of the link
+            Ok(target) => path = path.parent().map_or(target.clone(), |dir| dir.join(&target)),
+            Err(_) => break
+        }
+    }
+    path
+}
+
+// If saving can write over the file, which it keeps the permissions of
+pub fn writable(path: &Path) -> bool {
+    OpenOptions::new().write(true).open(resolve(path)).is_ok()
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push("~");
+    PathBuf::from(name)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_string());
+    path.with_file_name(format!(".{}.med-save-{}", name, process::id()))
+}
+
+#[cfg(unix)]
+fn copy_owner(meta: &fs::Metadata, path: &Path) {
+    use std::os::unix::fs::{chown, MetadataExt};
+    // only root can give a file away, everyone else keeps their own files as they were
+    chown(path, Some(meta.uid()), Some(meta.gid())).ok();
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_meta: &fs::Metadata, _path: &Path) {}
+
+// Makes a rename in `dir` survive a crash
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = File::open(dir) { dir.sync_all().ok(); }
+}
+
+fn write_temp<F>(file: File, temp: &Path, meta: Option<&fs::Metadata>, contents: F) -> io::Result<()>
+where F: FnOnce(&mut dyn Write) -> io::Result<()>, {
+    let mut out = BufWriter::new(file);
+    contents(&mut out)?;
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+
+    if let Some(meta) = meta {
+        copy_owner(meta, temp);
+        // after chown, which may clear the setuid / setgid bits
+        fs::set_permissions(temp, meta.permissions())?;
+    }
+    file.sync_all()
+}
+
+// The metadata of `path`, None if it does not exist yet
+fn metadata(path: &Path) -> io::Result<Option<fs::Metadata>> {
+    match fs::metadata(path) {
+        Ok(meta) => Ok(Some(meta)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+// Writes a file so that it either has the old or the new contents, never something in between:
+// the new contents go to a temporary file next to it, which is then renamed over it.
+// With `backup` the old contents are kept in `path~`.
+pub fn write<F>(path: &Path, backup: bool, contents: F) -> io::Result<()>
+where F: FnOnce(&mut dyn Write) -> io::Result<()>, {
+    let path = resolve(path);
+    let meta = metadata(&path)?;
+    replace(&path, meta, backup, true, contents)
+}
+
+// Like write, but failing where write would write over the file in place, for a file that is
+// mapped into memory and must not change while it is
+pub fn write_by_rename<F>(path: &Path, backup: bool, contents: F) -> io::Result<()>
+where F: FnOnce(&mut dyn Write) -> io::Result<()>, {
+    let path = resolve(path);
+    let meta = metadata(&path)?;
+    replace(&path, meta, backup, false, contents)
+}
+
+// Like write, but the file gets the permissions and owner of `like`, eg. so that a copy of a
+// private file is just as private
+pub fn write_like<F>(path: &Path, like: &Path, contents: F) -> io::Result<()>
+where F: FnOnce(&mut dyn Write) -> io::Result<()>, {
+    let meta = metadata(like)?;
+    replace(&resolve(path), meta, false, true, contents)
+}
+
+fn replace<F>(path: &Path, meta: Option<fs::Metadata>, backup: bool, in_place: bool, contents: F) -> io::Result<()>
+where F: FnOnce(&mut dyn Write) -> io::Result<()>, {
+    let temp = temp_path(path);
+    // a leftover from a save that was interrupted
+    fs::remove_file(&temp).ok();
+    let file = match OpenOptions::new().write(true).create_new(true).open(&temp) {
+        Ok(file) => file,
+        // a file that can be written to in a directory that cannot still gets saved, just not safely
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied && meta.is_some() => {
+            if !in_place {
+                return Err(io::Error::new(e.kind(), "the directory is not writable and the file cannot be written over in place"));
+            }
+            return overwrite(path, backup, contents);
+        }
+        Err(e) => return Err(e)
+    };
+    if let Err(e) = write_temp(file, &temp, meta.as_ref(), contents) {
+        fs::remove_file(&temp).ok();
+        return Err(e);
+    }
+
+    if backup && meta.is_some() {
+        if let Err(e) = fs::copy(path, backup_path(path)) {
+            fs::remove_file(&temp).ok();
+            return Err(e);
+        }
+    }
+    if let Err(e) = fs::rename(&temp, path) {
+        fs::remove_file(&temp).ok();
+        return Err(e);
+    }
+
+    sync_dir(path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")));
+    Ok(())
+}
+
+// Writes over the file itself, for when there can be no temporary file next to it. The contents
+// are put together first, so that failing to (eg. a line the encoding cannot hold) leaves the
+// file as it was.
+fn overwrite<F>(path: &Path, backup: bool, contents: F) -> io::Result<()>
+where F: FnOnce(&mut dyn Write) -> io::Result<()>, {
+    let mut bytes = Vec::new();
+    contents(&mut bytes)?;
+    if backup { fs::copy(path, backup_path(path))?; }
+
+    let mut file = OpenOptions::new().write(true).truncate(true).open(path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory of its own for each test, as they run side by side
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("med-test-{}-atomic-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn text(text: &'static str) -> impl FnOnce(&mut dyn Write) -> io::Result<()> {
+        move |out| out.write_all(text.as_bytes())
+    }
+
+    #[test]
+    fn writes_and_keeps_a_backup() {
+        let dir = temp_dir("backup");
+        let path = dir.join("file");
+        write(&path, true, text("new file")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new file");
+        // nothing to back up the first time
+        assert!(!backup_path(&path).exists());
+
+        write(&path, true, text("second")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::read_to_string(backup_path(&path)).unwrap(), "new file");
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn failing_contents_leave_the_file_alone() {
+        let dir = temp_dir("failing");
+        let path = dir.join("file");
+        fs::write(&path, "old").unwrap();
+        let failed = write(&path, false, |out| {
+            out.write_all(b"half")?;
+            Err(io::Error::other("cannot encode"))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn overwrites_in_place() {
+        let dir = temp_dir("in-place");
+        let path = dir.join("file");
+        fs::write(&path, "a longer old text").unwrap();
+        overwrite(&path, true, text("new")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_to_string(backup_path(&path)).unwrap(), "a longer old text");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_through_symlinks() {
+        use std::os::unix::fs::symlink;
+        let dir = temp_dir("symlink");
+        fs::create_dir_all(dir.join("real")).unwrap();
+        fs::write(dir.join("real/file"), "old").unwrap();
+        // a relative link to a link
+        symlink("real/file", dir.join("link")).unwrap();
+        symlink(dir.join("link"), dir.join("link2")).unwrap();
+        assert_eq!(resolve(&dir.join("link2")), dir.join("real/file"));
+
+        write(&dir.join("link2"), false, text("new")).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("real/file")).unwrap(), "new");
+        assert!(fs::symlink_metadata(dir.join("link")).unwrap().file_type().is_symlink());
+        // a link to a file that does not exist yet creates it
+        symlink("real/new", dir.join("dangling")).unwrap();
+        write(&dir.join("dangling"), false, text("created")).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("real/new")).unwrap(), "created");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_the_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("mode");
+        let (path, like) = (dir.join("file"), dir.join("private"));
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o751)).unwrap();
+        write(&path, false, text("new")).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o751);
+
+        fs::write(&like, "").unwrap();
+        fs::set_permissions(&like, fs::Permissions::from_mode(0o600)).unwrap();
+        write_like(&dir.join("copy"), &like, text("copy")).unwrap();
+        assert_eq!(fs::metadata(dir.join("copy")).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    // Only someone other than root is kept out of a directory without write permission
+    #[cfg(unix)]
+    #[test]
+    fn unwritable_directory() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("unwritable");
+        let path = dir.join("file");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+        let locked = File::create(dir.join("probe")).is_err();
+
+        let by_rename = write_by_rename(&path, false, text("renamed"));
+        assert_eq!(by_rename.is_err(), locked);
+        write(&path, false, text("new")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,203 @@
+use std::convert::TryFrom;
+
+pub static BOM: &str = "\u{feff}";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1
+}
+
+// How the lines of a file are put into bytes, kept so that saving writes them back the same way
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Format {
+    pub encoding: Encoding,
+    pub crlf: bool,
+    pub final_newline: bool, // if the last line ends with a newline too
+    pub bom: bool // UTF-16 files always get one, as that is how they are told apart
+}
+
+impl Default for Format {
+    // what new files get
+    fn default() -> Format {
+        Format { encoding: Encoding::Utf8, crlf: false, final_newline: true, bom: false }
+    }
+}
+
+impl Encoding {
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Latin1 => "Latin-1"
+        }
+    }
+
+    // Guesses the encoding from a UTF-16 BOM or else from the bytes being valid UTF-8, anything
+    // else is taken as Latin-1, which every sequence of bytes is
+    fn detect(bytes: &[u8]) -> Encoding {
+        if bytes.starts_with(&[0xff, 0xfe]) {
+            Encoding::Utf16Le
+        } else if bytes.starts_with(&[0xfe, 0xff]) {
+            Encoding::Utf16Be
+        } else if std::str::from_utf8(bytes).is_ok() {
+            Encoding::Utf8
+        } else {
+            Encoding::Latin1
+        }
+    }
+
+    // Text in UTF-16 that is cut short or has unpaired surrogates gets replacement characters
+    fn decode(&self, bytes: &[u8]) -> String {
+        let utf16 = |unit: fn([u8; 2]) -> u16| {
+            let units = bytes.chunks(2).map(|pair| match pair {
+                [a, b] => unit([*a, *b]),
+                _ => 0xfffd
+            });
+            char::decode_utf16(units).map(|ch| ch.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+        };
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).to_string(),
+            Encoding::Utf16Le => utf16(u16::from_le_bytes),
+            Encoding::Utf16Be => utf16(u16::from_be_bytes),
+            Encoding::Latin1 => bytes.iter().map(|&byte| byte as char).collect()
+        }
+    }
+
+    // None if the text has characters that the encoding cannot hold
+    pub fn encode(&self, text: &str) -> Option<Vec<u8>> {
+        match self {
+            Encoding::Utf8 => Some(text.as_bytes().to_vec()),
+            Encoding::Utf16Le => Some(text.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect()),
+            Encoding::Utf16Be => Some(text.encode_utf16().flat_map(|unit| unit.to_be_bytes()).collect()),
+            Encoding::Latin1 => text.chars().map(|ch| u8::try_from(ch).ok()).collect()
+        }
+    }
+}
+
+pub fn is_utf16(bytes: &[u8]) -> bool {
+    bytes.starts_with(&[0xff, 0xfe]) || bytes.starts_with(&[0xfe, 0xff])
+}
+
+// Text does not have NUL bytes in it, except in UTF-16 where they are half of every ASCII char.
+// Only the start of the file is looked at, which is enough to tell.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    !is_utf16(bytes) && bytes.iter().take(8000).any(|&byte| byte == 0)
+}
+
+impl Format {
+
+    // Decodes a whole file and splits it into lines, detecting its format on the way. It is CRLF
+    // only if every line ends with it: with mixed line endings the lines keep their \r, so that
+    // saving writes them back as they were.
+    pub fn split(bytes: &[u8]) -> (Format, Vec<String>) {
+        let encoding = Encoding::detect(bytes);
+        let text = encoding.decode(bytes);
+        let (bom, text) = match text.strip_prefix(BOM) {
+            Some(rest) => (encoding == Encoding::Utf8, rest),
+            None => (false, text.as_str())
+        };
+        let newlines = text.matches('\n').count();
+        let crlf = newlines > 0 && text.matches("\r\n").count() == newlines;
+        let final_newline = text.ends_with('\n');
+
+        let mut lines: Vec<String> = text.split('\n').map(|line| line.to_string()).collect();
+        // the last line has no line ending, a \r it ends with is part of it
+        if crlf {
+            let ended = lines.len() - 1;
+            for line in lines[..ended].iter_mut() { line.pop(); }
+        }
+        // the empty string after the last newline is not a line
+        if final_newline { lines.pop(); }
+
+        (Format { encoding, crlf, final_newline, bom }, lines)
+    }
+
+    pub fn newline(&self) -> &'static str {
+        if self.crlf { "\r\n" } else { "\n" }
+    }
+
+    // What goes before the first line
+    pub fn bom(&self) -> &'static str {
+        match self.encoding {
+            Encoding::Utf16Le | Encoding::Utf16Be => BOM,
+            Encoding::Utf8 if self.bom => BOM,
+            _ => ""
+        }
+    }
+
+    // eg. "UTF-8, CRLF, BOM", shown in the status line
+    pub fn describe(&self) -> String {
+        let mut parts = vec![self.encoding.name(), if self.crlf { "CRLF" } else { "LF" }];
+        if self.bom && self.encoding == Encoding::Utf8 { parts.push("BOM"); }
+        if !self.final_newline { parts.push("no final newline"); }
+        parts.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_endings() {
+        let (format, lines) = Format::split(b"a\r\nb\r\n");
+        assert!(format.crlf && format.final_newline);
+        assert_eq!(lines, vec!["a", "b"]);
+
+        let (format, lines) = Format::split(b"a\nb");
+        assert!(!format.crlf && !format.final_newline);
+        assert_eq!(lines, vec!["a", "b"]);
+
+        // a \r at the very end is not part of a line ending
+        let (format, lines) = Format::split(b"a\r\nb\r");
+        assert!(format.crlf);
+        assert_eq!(lines, vec!["a", "b\r"]);
+    }
+
+    #[test]
+    fn mixed_line_endings_are_kept() {
+        for text in [&b"a\r\nb\nc\r\n"[..], b"a\nb\r\nc\n"] {
+            let (format, lines) = Format::split(text);
+            assert!(!format.crlf);
+            assert_eq!(lines.join("\n") + "\n", String::from_utf8_lossy(text));
+        }
+    }
+
+    #[test]
+    fn empty_file_is_one_empty_line() {
+        let (format, lines) = Format::split(b"");
+        assert_eq!(lines, vec![""]);
+        assert!(!format.final_newline);
+        assert_eq!(Format::split(b"\n").1, vec![""]);
+    }
+
+    #[test]
+    fn encodings() {
+        let (format, lines) = Format::split(b"\xef\xbb\xbfok\n");
+        assert_eq!((format.encoding, format.bom), (Encoding::Utf8, true));
+        assert_eq!(lines, vec!["ok"]);
+
+        let (format, lines) = Format::split(b"caf\xe9\n");
+        assert_eq!(format.encoding, Encoding::Latin1);
+        assert_eq!(lines, vec!["café"]);
+
+        let (format, lines) = Format::split(b"\xff\xfeh\0i\0\n\0");
+        assert_eq!(format.encoding, Encoding::Utf16Le);
+        assert_eq!(lines, vec!["hi"]);
+        assert_eq!(format.bom(), BOM);
+        assert_eq!(Encoding::Utf16Be.encode("hi"), Some(vec![0, b'h', 0, b'i']));
+        assert_eq!(Encoding::Latin1.encode("€"), None);
+    }
+
+    #[test]
+    fn binary_files() {
+        assert!(is_binary(b"ELF\0\x01"));
+        assert!(!is_binary(b"plain text"));
+        assert!(!is_binary(b"\xff\xfeh\0"));
+    }
+}
@@ -0,0 +1,472 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::atomic;
+
+// Positions are (line, column), both 0 indexed
+pub type Pos = (usize, usize);
+
+static MAGIC: &str = "med-undo 1";
+
+#[derive(Debug, Clone)]
+pub enum Edit {
+    Insert { line: usize, col: usize, text: String },
+    Remove { line: usize, col: usize, text: String }
+}
+
+// A group of edits that are undone / redone together
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub edits: Vec<Edit>,
+    pub before: Pos,
+    pub after: Pos
+}
+
+// Walking the tree from one state to another is a sequence of these
+pub enum Move {
+    Undo(Step),
+    Redo(Step)
+}
+
+// Every state of the buffer is a node, node 0 is the state the file was first opened in.
+// Nodes are never removed and their index is the order in which they were created.
+#[derive(Debug)]
+struct Node {
+    step: Step,
+    parent: usize,
+    children: Vec<usize>,
+    redo_child: usize, // the branch that redo follows
+    time: u64
+}
+
+#[derive(Debug)]
+pub struct History {
+    nodes: Vec<Node>,
+    current: usize,
+    grouping: bool,
+    changes: u64 // counts every change to the buffer, including undo and redo
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// The hidden file next to `path` that keeps its history
+pub fn undo_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_string());
+    path.with_file_name(format!(".{}.med-undo", name))
+}
+
+// FNV-1a, used to check that the history still belongs to the file
+pub fn hash_lines<'a, I>(lines: I) -> u64
+where I: Iterator<Item = &'a str>, {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for line in lines {
+        for byte in line.bytes().chain(std::iter::once(b'\n')) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' { out.push(ch); continue; }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Edit {
+
+    // Tries to fold `next` into self, so that consecutive typing or deleting is one edit
+    fn merge(&mut self, next: &Edit) -> bool {
+        match (self, next) {
+            (Edit::Insert { line, col, text }, Edit::Insert { line: nline, col: ncol, text: ntext }) => {
+                if line != nline || text.contains('\n') || ntext.contains('\n') { return false; }
+                if *ncol != *col + text.chars().count() { return false; }
+                text.push_str(ntext);
+                true
+            }
+            (Edit::Remove { line, col, text }, Edit::Remove { line: nline, col: ncol, text: ntext }) => {
+                if line != nline || text.contains('\n') || ntext.contains('\n') { return false; }
+                if *ncol == *col {
+                    // delete
+                    text.push_str(ntext);
+                    true
+                } else if *ncol + ntext.chars().count() == *col {
+                    // backspace
+                    text.insert_str(0, ntext);
+                    *col = *ncol;
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false
+        }
+    }
+
+    fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        match self {
+            Edit::Insert { line, col, text } => writeln!(out, "I {} {} {}", line, col, escape(text)),
+            Edit::Remove { line, col, text } => writeln!(out, "R {} {} {}", line, col, escape(text))
+        }
+    }
+
+    fn read(entry: &str) -> io::Result<Edit> {
+        let mut parts = entry.splitn(4, ' ');
+        let kind = parts.next();
+        let line = parts.next().and_then(|p| p.parse().ok()).ok_or_else(|| invalid("bad edit line"))?;
+        let col = parts.next().and_then(|p| p.parse().ok()).ok_or_else(|| invalid("bad edit column"))?;
+        let text = unescape(parts.next().unwrap_or(""));
+        match kind {
+            Some("I") => Ok(Edit::Insert { line, col, text }),
+            Some("R") => Ok(Edit::Remove { line, col, text }),
+            _ => Err(invalid("bad edit kind"))
+        }
+    }
+}
+
+impl History {
+
+    pub fn init() -> History {
+        History {
+            nodes: vec![Node {
+                step: Step { edits: Vec::new(), before: (0, 0), after: (0, 0) },
+                parent: 0,
+                children: Vec::new(),
+                redo_child: 0,
+                time: now()
+            }],
+            current: 0,
+            grouping: false,
+            changes: 0
+        }
+    }
+
+    // Records an edit made with the cursor going from `before` to `after`
+    pub fn record(&mut self, edit: Edit, before: Pos, after: Pos) {
+        let current = self.current;
+
+        if self.grouping && current != 0 && self.nodes[current].children.is_empty() {
+            let node = &mut self.nodes[current];
+            if node.step.after == before && node.step.edits.last_mut().unwrap().merge(&edit) {
+                node.step.after = after;
+                node.time = now();
+                self.changes += 1;
+                return;
+            }
+        }
+
+        self.record_step(vec![edit], before, after);
+        self.grouping = true;
+    }
+
+    // Records edits that are always undone together and never merged with others
+    pub fn record_step(&mut self, edits: Vec<Edit>, before: Pos, after: Pos) {
+        let current = self.current;
+        self.nodes.push(Node {
+            step: Step { edits, before, after },
+            parent: current,
+            children: Vec::new(),
+            redo_child: 0,
+            time: now()
+        });
+        let new = self.nodes.len() - 1;
+        self.nodes[current].children.push(new);
+        self.nodes[current].redo_child = new;
+        self.current = new;
+        self.grouping = false;
+        self.changes += 1;
+    }
+
+    // The moves that take the buffer from the current state to `target`
+    pub fn travel(&mut self, target: usize) -> Vec<Move> {
+        self.grouping = false;
+
+        let mut up = Vec::new();
+        let mut down = Vec::new();
+        let (mut from, mut to) = (self.current, target);
+        // a parent is always created before its children, so step back the newer of the two
+        while from != to {
+            if from > to {
+                up.push(from);
+                from = self.nodes[from].parent;
+            } else {
+                down.push(to);
+                to = self.nodes[to].parent;
+            }
+        }
+
+        let mut moves: Vec<Move> = up.iter().map(|&n| Move::Undo(self.nodes[n].step.clone())).collect();
+        for &n in down.iter().rev() {
+            let parent = self.nodes[n].parent;
+            self.nodes[parent].redo_child = n;
+            moves.push(Move::Redo(self.nodes[n].step.clone()));
+        }
+        if !moves.is_empty() { self.changes += 1; }
+        self.current = target;
+        moves
+    }
+
+    pub fn undo(&mut self) -> Vec<Move> {
+        let parent = self.nodes[self.current].parent;
+        self.travel(parent)
+    }
+
+    pub fn redo(&mut self) -> Vec<Move> {
+        match self.nodes[self.current].children.is_empty() {
+            true => Vec::new(),
+            false => self.travel(self.nodes[self.current].redo_child)
+        }
+    }
+
+    // Makes redo follow the next branch from the current state, returns (branch, number of branches)
+    pub fn next_branch(&mut self) -> (usize, usize) {
+        let node = &mut self.nodes[self.current];
+        if node.children.is_empty() { return (0, 0); }
+        let i = node.children.iter().position(|&c| c == node.redo_child).unwrap_or(0);
+        let i = (i + 1) % node.children.len();
+        node.redo_child = node.children[i];
+        (i + 1, node.children.len())
+    }
+
+    // The state created just before / after the current one, across all branches
+    pub fn earlier(&mut self, steps: usize) -> Vec<Move> {
+        self.travel(self.current.saturating_sub(steps))
+    }
+
+    pub fn later(&mut self, steps: usize) -> Vec<Move> {
+        self.travel((self.current + steps).min(self.nodes.len() - 1))
+    }
+
+    // The newest state that is at least `secs` older / at most `secs` newer than the current one
+    pub fn earlier_by_time(&mut self, secs: u64) -> Vec<Move> {
+        let limit = self.nodes[self.current].time.saturating_sub(secs);
+        let target = (0..self.current).rev().find(|&n| self.nodes[n].time <= limit).unwrap_or(0);
+        self.travel(target)
+    }
+
+    pub fn later_by_time(&mut self, secs: u64) -> Vec<Move> {
+        let limit = self.nodes[self.current].time + secs;
+        let target = (self.current..self.nodes.len()).rev().find(|&n| self.nodes[n].time <= limit).unwrap_or(self.current);
+        self.travel(target)
+    }
+
+    // (current state, number of states, seconds since the current state was made)
+    pub fn position(&self) -> (usize, usize, u64) {
+        (self.current, self.nodes.len() - 1, now().saturating_sub(self.nodes[self.current].time))
+    }
+
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    // Keeps the next edit out of the current state, eg. so that the state saved to disk stays
+    // what was saved
+    pub fn seal(&mut self) {
+        self.grouping = false;
+    }
+
+    // Writes the history to `path`, as private as the file `like` it belongs to since it holds
+    // all the text typed and removed
+    pub fn save(&self, path: &Path, like: &Path, hash: u64) -> io::Result<()> {
+        atomic::write_like(path, like, |out| self.write(out, hash))
+    }
+
+    fn write(&self, out: &mut dyn Write, hash: u64) -> io::Result<()> {
+        writeln!(out, "{}", MAGIC)?;
+        writeln!(out, "hash {:016x}", hash)?;
+        writeln!(out, "current {}", self.current)?;
+        for node in self.nodes.iter() {
+            let step = &node.step;
+            writeln!(out, "node {} {} {} {} {} {} {} {}",
+                node.parent, node.redo_child, node.time,
+                step.before.0, step.before.1, step.after.0, step.after.1, step.edits.len())?;
+            for edit in step.edits.iter() { edit.write(out)?; }
+        }
+        Ok(())
+    }
+
+    // Loads the history saved for a file whose contents hash to `hash`
+    pub fn load(path: &Path, hash: u64) -> io::Result<History> {
+        let text = std::fs::read_to_string(path)?;
+        let mut lines = text.lines();
+
+        if next_line(&mut lines)? != MAGIC { return Err(invalid("not a med history file")); }
+        if next_line(&mut lines)? != format!("hash {:016x}", hash) {
+            return Err(invalid("history belongs to another version of the file"));
+        }
+        let current: usize = next_line(&mut lines)?.strip_prefix("current ").and_then(|n| n.parse().ok())
+                             .ok_or_else(|| invalid("bad current state"))?;
+
+        let mut nodes: Vec<Node> = Vec::new();
+        while let Some(header) = lines.next() {
+            let nums = header.strip_prefix("node ")
+                .and_then(|nums| nums.split(' ').map(|n| n.parse::<u64>().ok()).collect::<Option<Vec<_>>>());
+            let nums = match nums {
+                Some(nums) if nums.len() == 8 => nums,
+                _ => return Err(invalid("bad history node"))
+            };
+            let mut edits = Vec::new();
+            for _ in 0..nums[7] { edits.push(Edit::read(next_line(&mut lines)?)?); }
+            nodes.push(Node {
+                step: Step {
+                    edits,
+                    before: (nums[3] as usize, nums[4] as usize),
+                    after: (nums[5] as usize, nums[6] as usize)
+                },
+                parent: nums[0] as usize,
+                children: Vec::new(),
+                redo_child: nums[1] as usize,
+                time: nums[2]
+            });
+        }
+
+        // every index has to point at a node, or walking the tree would go off it
+        if nodes.is_empty() || current >= nodes.len() || nodes[0].parent != 0 { return Err(invalid("bad history")); }
+        for n in 1..nodes.len() {
+            let parent = nodes[n].parent;
+            if parent >= n { return Err(invalid("bad history parent")); }
+            nodes[parent].children.push(n);
+        }
+        for node in nodes.iter() {
+            let redo_child = match node.children.is_empty() {
+                true => node.redo_child == 0,
+                false => node.children.contains(&node.redo_child)
+            };
+            if !redo_child { return Err(invalid("bad history redo branch")); }
+        }
+
+        Ok(History { nodes, current, grouping: false, changes: 0 })
+    }
+}
+
+fn next_line<'a>(lines: &mut std::str::Lines<'a>) -> io::Result<&'a str> {
+    lines.next().ok_or_else(|| invalid("truncated history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn insert(history: &mut History, line: usize, text: &str) {
+        history.record_step(vec![Edit::Insert { line, col: 0, text: text.to_string() }], (line, 0), (line, 1));
+    }
+
+    // The text of the edits made (or undone, in brackets) by walking the tree
+    fn walked(moves: Vec<Move>) -> Vec<String> {
+        let text = |step: &Step| match &step.edits[0] {
+            Edit::Insert { text, .. } | Edit::Remove { text, .. } => text.clone()
+        };
+        moves.iter().map(|mv| match mv {
+            Move::Undo(step) => format!("({})", text(step)),
+            Move::Redo(step) => text(step)
+        }).collect()
+    }
+
+    #[test]
+    fn branches_after_undo() {
+        let mut history = History::init();
+        insert(&mut history, 0, "a");
+        insert(&mut history, 0, "b");
+        assert_eq!(walked(history.undo()), vec!["(b)"]);
+        // typing after an undo starts a branch instead of dropping the undone state
+        insert(&mut history, 0, "c");
+        assert_eq!(history.position().0, 3);
+        assert_eq!(walked(history.undo()), vec!["(c)"]);
+        assert_eq!(walked(history.redo()), vec!["c"]);
+        history.undo();
+        assert_eq!(history.next_branch(), (1, 2));
+        assert_eq!(walked(history.redo()), vec!["b"]);
+        // going to the other branch undoes this one first
+        assert_eq!(walked(history.travel(3)), vec!["(b)", "c"]);
+        assert!(walked(history.redo()).is_empty());
+    }
+
+    #[test]
+    fn time_travel_goes_across_branches() {
+        let mut history = History::init();
+        for text in ["a", "b", "c"] { insert(&mut history, 0, text); }
+        history.undo();
+        history.undo();
+        insert(&mut history, 1, "d");
+        for (n, time) in [100, 200, 300, 400, 500].iter().enumerate() { history.nodes[n].time = *time; }
+
+        // states are numbered in the order they were made, whatever branch they are on
+        assert_eq!(walked(history.earlier(1)), vec!["(d)", "b", "c"]);
+        assert_eq!(walked(history.later(5)), vec!["(c)", "(b)", "d"]);
+        assert_eq!(walked(history.earlier_by_time(150)), vec!["(d)", "b"]);
+        assert_eq!(walked(history.later_by_time(100)), vec!["c"]);
+        assert!(history.earlier(10).len() == 3 && history.position().0 == 0);
+        assert!(history.earlier(1).is_empty());
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("med-test-{}-history-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let (file, path) = (temp_path("file"), temp_path("undo"));
+        fs::write(&file, "text").unwrap();
+        let mut history = History::init();
+        insert(&mut history, 0, "a");
+        history.record(Edit::Remove { line: 0, col: 1, text: "x\\y\r\n".to_string() }, (0, 1), (0, 1));
+        history.undo();
+        insert(&mut history, 2, "b");
+        history.undo();
+        history.save(&path, &file, 42).unwrap();
+
+        assert!(History::load(&path, 41).is_err());
+        let mut loaded = History::load(&path, 42).unwrap();
+        assert_eq!(loaded.position().0, 1);
+        assert_eq!(loaded.nodes.len(), 4);
+        // redo follows the branch it followed when it was saved
+        assert_eq!(walked(loaded.redo()), vec!["b"]);
+        assert_eq!(walked(loaded.travel(2)), vec!["(b)", "x\\y\r\n"]);
+        assert_eq!(walked(loaded.travel(0)), vec!["(x\\y\r\n)", "(a)"]);
+        fs::remove_file(&file).ok();
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn bad_files_have_no_history() {
+        let path = temp_path("bad");
+        let node = |parent, redo_child| format!("node {} {} 0 0 0 0 1 1\nI 0 0 a\n", parent, redo_child);
+        let bad = [
+            format!("{}\nhash {:016x}\ncurrent 2\n{}{}", MAGIC, 1, node(0, 1), node(0, 0)),
+            format!("{}\nhash {:016x}\ncurrent 0\n{}{}", MAGIC, 1, node(0, 5), node(0, 0)),
+            format!("{}\nhash {:016x}\ncurrent 0\n{}{}", MAGIC, 1, node(0, 1), node(0, 3)),
+            format!("{}\nhash {:016x}\ncurrent 0\n{}{}", MAGIC, 1, node(1, 1), node(0, 0)),
+            format!("{}\nhash {:016x}\ncurrent 0\n{}{}", MAGIC, 1, node(0, 1), node(1, 0)),
+            format!("{}\nhash {:016x}\ncurrent 0\nnode x 0 0 0 0 0 0 0 0\n", MAGIC, 1),
+            format!("{}\nhash {:016x}\ncurrent 0\nnode 0 0 0 0 0 0 0 1\n", MAGIC, 1)
+        ];
+        for (n, text) in bad.iter().enumerate() {
+            fs::write(&path, text).unwrap();
+            assert!(History::load(&path, 1).is_err(), "file {}", n);
+        }
+        fs::write(&path, format!("{}\nhash {:016x}\ncurrent 1\n{}{}", MAGIC, 1, node(0, 1), node(0, 0))).unwrap();
+        assert!(History::load(&path, 1).is_ok());
+        fs::remove_file(&path).ok();
+    }
+}
@@ -1,5 +1,5 @@
-use std::fs::File;
 use std::io::{Write};
+use unicode_segmentation::UnicodeSegmentation;
 
 #[derive(Debug)]
 pub struct Line {
@@ -19,10 +19,11 @@ impl Line {
     }
 
     pub fn init_with_line(newline: String) -> Line {
+        let nchars = newline.chars().count();
         let mut len = 32;
-        while len < newline.len() { len*= 2; }
+        while len <= nchars { len*= 2; }
         let mut text: Vec<char> = vec!['\0'; len];
-        let offset = len - newline.len();
+        let offset = len - nchars;
         for (i,ch) in newline.chars().enumerate() {
             text[offset+i] = ch;
         }
@@ -30,7 +31,7 @@ impl Line {
         Line {
             pre: 0,
             post: offset-1,
-            text: text
+            text
         }
     }
 
@@ -42,26 +43,11 @@ impl Line {
         self.pre
     }
 
+    #[allow(dead_code)]
     pub fn get_pre(&self) -> usize {
         self.pre
     }
 
-    pub fn get_post(&self) -> usize {
-        self.post
-    }
-
-    pub fn set_post(&mut self, newpost: usize) {
-        self.post = newpost;
-    }
-
-    pub fn get_len(&self) -> usize {
-        self.text.len()
-    }
-
-    pub fn get_text(&self, i: usize) -> char {
-        self.text[i]
-    }
-
     fn adjust_buffer(&mut self) {
         let free = self.post + 1 - self.pre;
         let cap = self.text.len();
@@ -77,40 +63,67 @@ impl Line {
         } 
         else if free > 32 && free as f32 > 0.75*self.text.len() as f32 {
             let post_len = cap-1-self.post;
-            let mut post_text: Vec<char> = Vec::with_capacity(post_len);
-            
-            for i in 0..post_len {
-                post_text[i] = self.text[cap-1-i];
-            } 
+            let post_text: Vec<char> = self.text[self.post+1..].to_vec();
 
             self.text.resize(cap/2, '\0');
-            
-            for i in 0..post_len {
-                self.text[cap/2-1-i] = post_text[i];
-            }
+            self.text[cap/2-post_len..].copy_from_slice(&post_text);
             self.post -= cap/2
         }
 
     }
 
+    // Makes room for n more chars in the gap with a single resize
+    fn reserve(&mut self, n: usize) {
+        let free = self.post + 1 - self.pre;
+        if free > n + 4 { return; }
+
+        let cap = self.text.len();
+        let used = cap - free;
+        let mut newcap = 2*cap;
+        while newcap - used <= n + 4 { newcap *= 2; }
+
+        self.text.resize(newcap, '\0');
+        self.text.copy_within(self.post+1..cap, self.post+1 + newcap-cap);
+        self.post += newcap - cap;
+    }
+
+    pub fn insert_str(&mut self, newtext: &str) {
+        self.reserve(newtext.chars().count());
+        for ch in newtext.chars() {
+            self.text[self.pre] = ch;
+            self.pre += 1;
+        }
+    }
+
+    // Removes the text after the cursor and returns it
+    pub fn split_off(&mut self) -> String {
+        let post_text: String = self.text[self.post+1..].iter().collect();
+        self.post = self.text.len() - 1;
+        post_text
+    }
+
     pub fn insert(&mut self, newchar: char) {
         self.adjust_buffer();
         self.text[self.pre] = newchar;
         self.pre += 1;
     }
 
+    // Backspace and delete remove a whole grapheme cluster, eg. a letter with its accents
     pub fn backspace(&mut self) -> bool {
-        if self.pre > 0 {
-            self.pre -= 1;
-            return true;
-        } return false;
+        let n = self.prev_grapheme().map_or(0, |g| g.chars().count());
+        self.pre -= n;
+        n > 0
     }
 
     pub fn delete(&mut self) -> bool {
-        if self.post + 1 < self.text.len() {
-            self.post += 1;
-            return true;
-        } return false;
+        let n = self.next_grapheme().map_or(0, |g| g.chars().count());
+        self.post += n;
+        n > 0
+    }
+
+    // Deletes up to n chars after the cursor at once
+    pub fn delete_n(&mut self, n: usize) {
+        self.post = (self.post + n).min(self.text.len() - 1);
     }
 
     pub fn move_l(&mut self) -> bool {
@@ -120,7 +133,7 @@ impl Line {
             self.post -= 1;
             return true;
         }
-        return false;
+        false
     }
 
     pub fn move_r(&mut self) -> bool {
@@ -130,7 +143,47 @@ impl Line {
             self.pre += 1;
             return true;
         }
-        return false;
+        false
+    }
+
+    // Moves over a whole grapheme cluster
+    pub fn move_grapheme_l(&mut self) -> bool {
+        let n = self.prev_grapheme().map_or(0, |g| g.chars().count());
+        for _ in 0..n { self.move_l(); }
+        n > 0
+    }
+
+    pub fn move_grapheme_r(&mut self) -> bool {
+        let n = self.next_grapheme().map_or(0, |g| g.chars().count());
+        for _ in 0..n { self.move_r(); }
+        n > 0
+    }
+
+    // Moves the cursor back to the start of the grapheme cluster it is in, if it is in the middle of one
+    pub fn snap(&mut self) {
+        let mut start = 0;
+        for grapheme in self.show().graphemes(true) {
+            let end = start + grapheme.chars().count();
+            if end > self.pre { break; }
+            start = end;
+        }
+        self.move_to(start);
+    }
+
+    pub fn move_to(&mut self, col: usize) {
+        while self.pre > col && self.move_l() { }
+        while self.pre < col && self.move_r() { }
+    }
+
+    // The grapheme clusters just before and after the cursor
+    pub fn prev_grapheme(&self) -> Option<String> {
+        let pre: String = self.text[..self.pre].iter().collect();
+        pre.graphemes(true).next_back().map(|g| g.to_string())
+    }
+
+    pub fn next_grapheme(&self) -> Option<String> {
+        let post: String = self.text[self.post+1..].iter().collect();
+        post.graphemes(true).next().map(|g| g.to_string())
     }
 
     pub fn move_start(&mut self) {
@@ -153,11 +206,14 @@ impl Line {
         line
     }
 
-    pub fn save(&self, file: &mut File) -> Result<(), std::io::Error> {
-        for i in 0..self.pre { write!(file, "{}", self.text[i])?; }
-        for i in self.post+1..self.text.len() { write!(file, "{}", self.text[i])?; }
-        write!(file, "\n")?;
+    // Writes the text without a line ending, returns the number of bytes written
+    pub fn save(&self, file: &mut dyn Write) -> Result<usize, std::io::Error> {
+        let mut bytes = 0;
+        for i in (0..self.pre).chain(self.post+1..self.text.len()) {
+            write!(file, "{}", self.text[i])?;
+            bytes += self.text[i].len_utf8();
+        }
 
-        Ok(())
+        Ok(bytes)
     }
 }    
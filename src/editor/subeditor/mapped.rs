@@ -0,0 +1,312 @@
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use memmap2::Mmap;
+use super::format::{Encoding, Format, BOM};
+use super::Store;
+
+static BLOCK: usize = 64; // lines per offset kept, the ones in between are found by reading the block
+static CHUNK: usize = 1 << 20; // bytes the indexer reads before handing over what it found
+
+// What the indexer found in one chunk
+struct Found {
+    starts: Vec<usize>, // of the blocks that start in the chunk
+    lines: usize, // lines started so far
+    done: bool
+}
+
+fn position(bytes: &[u8], byte: u8) -> Option<usize> {
+    bytes.iter().position(|&b| b == byte)
+}
+
+// Runs on its own thread, going through the file one chunk at a time
+fn index(map: Arc<Mmap>, skip: usize, scanned: Arc<AtomicUsize>, found: Sender<Found>) {
+    let (mut starts, mut lines, mut from) = (vec![skip], 1, skip);
+    loop {
+        let end = (from + CHUNK).min(map.len());
+        for (i, &byte) in map[from..end].iter().enumerate() {
+            let next = from + i + 1;
+            // a newline at the very end does not start another line
+            if byte == b'\n' && next < map.len() {
+                if lines % BLOCK == 0 { starts.push(next); }
+                lines += 1;
+            }
+        }
+        scanned.store(end, Ordering::Relaxed);
+        let done = end == map.len();
+        // the editor is gone when nobody is listening
+        if found.send(Found { starts: std::mem::take(&mut starts), lines, done }).is_err() || done { return; }
+        from = end;
+    }
+}
+
+// A file too big to read whole, mapped into memory and read a line at a time. Where its lines
+// start is found on a thread of its own, the lines found so far can be read meanwhile. Huge
+// files are taken to be UTF-8, bytes that are not are shown as U+FFFD but saved as they are.
+pub struct Mapped {
+    map: Arc<Mmap>,
+    format: Format,
+    skip: usize, // the BOM
+    starts: Vec<usize>, // of every BLOCK-th line
+    lines: usize,
+    scanned: Arc<AtomicUsize>,
+    found: Receiver<Found>,
+    done: bool
+}
+
+impl std::fmt::Debug for Mapped {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Mapped {{ {} bytes, {} lines }}", self.map.len(), self.lines)
+    }
+}
+
+impl Mapped {
+
+    pub fn open(file: &File) -> io::Result<Mapped> {
+        // the file can still be changed by others while it is mapped, which nothing can prevent.
+        // Truncating it makes reading the lost part fail, the price of opening instantly.
+        let map = Arc::new(unsafe { Mmap::map(file)? });
+        let skip = if map.starts_with(BOM.as_bytes()) { BOM.len() } else { 0 };
+        let crlf = position(&map[skip..], b'\n').is_some_and(|end| end > 0 && map[skip + end - 1] == b'\r');
+        let format = Format { encoding: Encoding::Utf8, crlf, final_newline: map.ends_with(b"\n"), bom: skip > 0 };
+
+        let scanned = Arc::new(AtomicUsize::new(0));
+        let (sender, found) = mpsc::channel();
+        let (shared, scanning) = (Arc::clone(&map), Arc::clone(&scanned));
+        thread::spawn(move || index(shared, skip, scanning, sender));
+
+        let mut mapped = Mapped { map, format, skip, starts: Vec::new(), lines: 0, scanned, found, done: false };
+        // the start of the file is there before the first frame is drawn
+        if let Ok(found) = mapped.found.recv() { mapped.take(found); }
+        Ok(mapped)
+    }
+
+    fn take(&mut self, found: Found) {
+        self.starts.extend(found.starts);
+        self.lines = found.lines;
+        self.done = found.done;
+    }
+
+    // Takes in what the indexer found since the last call, returns true if it found anything
+    pub fn poll(&mut self) -> bool {
+        let mut polled = false;
+        while let Ok(found) = self.found.try_recv() {
+            self.take(found);
+            polled = true;
+        }
+        polled
+    }
+
+    // Blocks until the whole file is indexed
+    pub fn wait(&mut self) {
+        while !self.done {
+            match self.found.recv() {
+                Ok(found) => self.take(found),
+                Err(_) => self.done = true
+            }
+        }
+    }
+
+    // How much of the file is indexed in percent, None once it is done
+    pub fn progress(&self) -> Option<usize> {
+        if self.done { return None; }
+        let len = (self.map.len() - self.skip).max(1);
+        Some(self.scanned.load(Ordering::Relaxed).saturating_sub(self.skip) * 100 / len)
+    }
+
+    // The lines known so far, the last line started may go on past what was scanned
+    pub fn num_lines(&self) -> usize {
+        if self.done { self.lines } else { self.lines.saturating_sub(1).max(1) }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    // Byte offset of the start of line n, the end of the file past the last line
+    fn start(&self, n: usize) -> usize {
+        if n >= self.lines { return self.map.len(); }
+        let mut start = self.starts[n / BLOCK];
+        for _ in 0..n % BLOCK {
+            start += position(&self.map[start..], b'\n').map_or(self.map.len() - start, |i| i + 1);
+        }
+        start
+    }
+
+    // Lines `from` to `to` as they are in the file, line endings included
+    pub fn bytes(&self, from: usize, to: usize) -> &[u8] {
+        &self.map[self.start(from)..self.start(to)]
+    }
+
+    // Line n as it is in the file, without its line ending
+    pub fn raw(&self, n: usize) -> &[u8] {
+        let start = self.start(n);
+        let end = position(&self.map[start..], b'\n').map_or(self.map.len(), |i| start + i);
+        let line = &self.map[start..end];
+        match self.format.crlf {
+            true => line.strip_suffix(b"\r").unwrap_or(line),
+            false => line
+        }
+    }
+
+    pub fn line(&self, n: usize) -> String {
+        String::from_utf8_lossy(self.raw(n)).into_owned()
+    }
+}
+
+// A run of lines of the buffer, in front of the part of the file that was never edited
+#[derive(Debug)]
+enum Piece {
+    File(usize, usize), // lines `from` to `to` of the file
+    Edited(Vec<String>)
+}
+
+impl Piece {
+
+    fn len(&self) -> usize {
+        match self {
+            Piece::File(from, to) => to - from,
+            Piece::Edited(lines) => lines.len()
+        }
+    }
+
+    // Keeps the first n lines of the piece, returns the rest
+    fn split_off(&mut self, n: usize) -> Piece {
+        match self {
+            Piece::File(from, to) => {
+                let rest = Piece::File(*from + n, *to);
+                *to = *from + n;
+                rest
+            }
+            Piece::Edited(lines) => Piece::Edited(lines.split_off(n))
+        }
+    }
+}
+
+// The lines of a mapped file with the edits made to them. Only edited lines are kept in memory,
+// the others stay runs of lines of the file, so an edit far away does not make the lines in
+// between take up memory and they can still be saved by copying them.
+#[derive(Debug)]
+pub struct MappedLines {
+    mapped: Mapped,
+    pieces: Vec<Piece>,
+    rest: usize // first line of the file after the pieces, where it goes on unedited
+}
+
+impl MappedLines {
+
+    pub fn new(mapped: Mapped) -> MappedLines {
+        MappedLines { mapped, pieces: Vec::new(), rest: 0 }
+    }
+
+    pub fn format(&self) -> Format {
+        self.mapped.format()
+    }
+
+    fn pieces_len(&self) -> usize {
+        self.pieces.iter().map(Piece::len).sum()
+    }
+
+    // The piece that line n is in and its line in it, or else its line of the file past the pieces
+    fn locate(&self, n: usize) -> Result<(usize, usize), usize> {
+        let mut start = 0;
+        for (i, piece) in self.pieces.iter().enumerate() {
+            if n < start + piece.len() { return Ok((i, n - start)); }
+            start += piece.len();
+        }
+        Err(self.rest + n - start)
+    }
+
+    // Makes a piece start at line n, returns its index
+    fn split(&mut self, n: usize) -> usize {
+        let len = self.pieces_len();
+        if n > len {
+            self.pieces.push(Piece::File(self.rest, self.rest + n - len));
+            self.rest += n - len;
+        }
+        match self.locate(n) {
+            Ok((i, 0)) => i,
+            Ok((i, k)) => {
+                let rest = self.pieces[i].split_off(k);
+                self.pieces.insert(i + 1, rest);
+                i + 1
+            }
+            Err(_) => self.pieces.len()
+        }
+    }
+
+    // Line n of the file, if line n of the buffer was not edited
+    fn file_line(&self, n: usize) -> Option<usize> {
+        match self.locate(n) {
+            Ok((i, k)) => match &self.pieces[i] {
+                Piece::File(from, _) => Some(from + k),
+                Piece::Edited(_) => None
+            },
+            Err(n) => Some(n)
+        }
+    }
+
+    fn line(&self, n: usize) -> String {
+        match self.locate(n) {
+            Ok((i, k)) => match &self.pieces[i] {
+                Piece::File(from, _) => self.mapped.line(from + k),
+                Piece::Edited(lines) => lines[k].clone()
+            },
+            Err(n) => self.mapped.line(n)
+        }
+    }
+}
+
+impl Store for MappedLines {
+
+    fn num_lines(&self) -> usize {
+        self.pieces_len() + self.mapped.num_lines().saturating_sub(self.rest)
+    }
+
+    fn lines(&self, from: usize, to: usize) -> Vec<String> {
+        (from..to.min(self.num_lines())).map(|n| self.line(n)).collect()
+    }
+
+    fn replace(&mut self, from: usize, to: usize, lines: &[String]) {
+        let first = self.split(from);
+        let last = self.split(to);
+        let edited = Some(Piece::Edited(lines.to_vec())).filter(|piece| piece.len() > 0);
+        self.pieces.splice(first..last, edited);
+    }
+
+    fn run(&self, n: usize, newline: &str) -> Result<(&[u8], usize), usize> {
+        let (from, to) = match self.locate(n) {
+            Ok((i, k)) => match &self.pieces[i] {
+                Piece::File(from, to) => (from + k, *to),
+                Piece::Edited(lines) => return Err(lines.len() - k)
+            },
+            Err(from) => (from, self.mapped.num_lines())
+        };
+        if newline != self.mapped.format().newline() { return Err(to - from); }
+        // the last line of the file and of the buffer end as the final newline says
+        let end = to.min(self.mapped.num_lines() - 1).min(from + self.num_lines() - 1 - n);
+        match end > from {
+            true => Ok((self.mapped.bytes(from, end), end - from)),
+            false => Err(1)
+        }
+    }
+
+    fn raw(&self, n: usize) -> Option<&[u8]> {
+        self.file_line(n).map(|n| self.mapped.raw(n))
+    }
+
+    fn progress(&self) -> Option<usize> {
+        self.mapped.progress()
+    }
+
+    fn poll(&mut self) -> bool {
+        self.mapped.poll()
+    }
+
+    fn wait(&mut self) {
+        self.mapped.wait()
+    }
+}
@@ -0,0 +1,206 @@
+use super::history::Pos;
+
+// A run of text in one of the two buffers, as byte offsets into it
+#[derive(Debug, Clone, Copy)]
+struct Piece {
+    add: bool, // in the append buffer rather than the original text
+    start: usize,
+    len: usize
+}
+
+// Text kept as the original file, which is never changed, and a buffer that everything typed is
+// appended to. The document is the pieces of the two read one after the other, so an edit only
+// splits or adds a piece instead of moving the text after it.
+#[derive(Debug)]
+pub struct PieceTable {
+    original: String,
+    add: String,
+    // where the newlines are in each buffer, so that finding a line does not read the text before it
+    original_newlines: Vec<usize>,
+    add_newlines: Vec<usize>,
+    pieces: Vec<Piece>,
+    lines: usize
+}
+
+fn newlines(text: &str, offset: usize) -> impl Iterator<Item = usize> + '_ {
+    text.bytes().enumerate().filter(|&(_, byte)| byte == b'\n').map(move |(i, _)| offset + i)
+}
+
+impl PieceTable {
+
+    pub fn new(original: String) -> PieceTable {
+        let original_newlines: Vec<usize> = newlines(&original, 0).collect();
+        let pieces = match original.len() {
+            0 => Vec::new(),
+            len => vec![Piece { add: false, start: 0, len }]
+        };
+        PieceTable {
+            lines: original_newlines.len() + 1,
+            original,
+            add: String::new(),
+            original_newlines,
+            add_newlines: Vec::new(),
+            pieces
+        }
+    }
+
+    fn buffer(&self, add: bool) -> (&str, &[usize]) {
+        if add { (&self.add, &self.add_newlines) } else { (&self.original, &self.original_newlines) }
+    }
+
+    fn text_of(&self, piece: &Piece) -> &str {
+        &self.buffer(piece.add).0[piece.start..piece.start + piece.len]
+    }
+
+    // Offsets into the newlines of the piece's buffer of the first and past the last one in it
+    fn newlines_in(&self, piece: &Piece) -> (usize, usize) {
+        let newlines = self.buffer(piece.add).1;
+        (newlines.partition_point(|&i| i < piece.start), newlines.partition_point(|&i| i < piece.start + piece.len))
+    }
+
+    // The document from byte `offset` on, one piece at a time
+    fn slices_from(&self, offset: usize) -> impl Iterator<Item = &str> + '_ {
+        let mut start = 0;
+        self.pieces.iter().filter_map(move |piece| {
+            let piece_start = start;
+            start += piece.len;
+            if start <= offset { return None; }
+            Some(&self.text_of(piece)[offset.saturating_sub(piece_start)..])
+        })
+    }
+
+    // Byte offset of the start of line n
+    fn line_start(&self, n: usize) -> Option<usize> {
+        if n == 0 { return Some(0); }
+        let (mut seen, mut offset) = (0, 0);
+        for piece in self.pieces.iter() {
+            let (first, last) = self.newlines_in(piece);
+            if seen + last - first >= n {
+                let newline = self.buffer(piece.add).1[first + n - seen - 1];
+                return Some(offset + newline - piece.start + 1);
+            }
+            seen += last - first;
+            offset += piece.len;
+        }
+        None
+    }
+
+    // Byte offset of a position, clamped to the end of its line and to the end of the text
+    fn offset(&self, (line, col): Pos) -> usize {
+        let start = match self.line_start(line) {
+            Some(start) => start,
+            None => return self.pieces.iter().map(|piece| piece.len).sum()
+        };
+        let mut len = 0;
+        let chars = self.slices_from(start).flat_map(|text| text.chars());
+        for ch in chars.take_while(|&ch| ch != '\n').take(col) { len += ch.len_utf8(); }
+        start + len
+    }
+
+    // The piece that byte `offset` is in and where in it, or one past the last piece at the end
+    fn locate(&self, offset: usize) -> (usize, usize) {
+        let mut start = 0;
+        for (i, piece) in self.pieces.iter().enumerate() {
+            if offset < start + piece.len { return (i, offset - start); }
+            start += piece.len;
+        }
+        (self.pieces.len(), 0)
+    }
+
+    // Makes a piece start at byte `offset`, returns its index
+    fn split(&mut self, offset: usize) -> usize {
+        let (i, inner) = self.locate(offset);
+        if inner == 0 { return i; }
+        let piece = self.pieces[i];
+        self.pieces[i].len = inner;
+        self.pieces.insert(i + 1, Piece { add: piece.add, start: piece.start + inner, len: piece.len - inner });
+        i + 1
+    }
+
+    pub fn num_lines(&self) -> usize {
+        self.lines
+    }
+
+    pub fn line(&self, n: usize) -> Option<String> {
+        let start = self.line_start(n)?;
+        Some(self.slices_from(start).flat_map(|text| text.chars()).take_while(|&ch| ch != '\n').collect())
+    }
+
+    pub fn get_range(&self, start: Pos, end: Pos) -> String {
+        let (start, end) = (self.offset(start), self.offset(end));
+        let mut text = String::new();
+        for slice in self.slices_from(start) {
+            let left = end.saturating_sub(start + text.len());
+            if left == 0 { break; }
+            text.push_str(&slice[..left.min(slice.len())]);
+        }
+        text
+    }
+
+    pub fn insert(&mut self, pos: Pos, text: &str) {
+        if text.is_empty() { return; }
+        let offset = self.offset(pos);
+        let start = self.add.len();
+        self.add_newlines.extend(newlines(text, start));
+        self.add.push_str(text);
+        self.lines += text.matches('\n').count();
+
+        let i = self.split(offset);
+        // typing goes on where the last piece typed ends, so it just grows
+        if i > 0 && self.pieces[i - 1].add && self.pieces[i - 1].start + self.pieces[i - 1].len == start {
+            self.pieces[i - 1].len += text.len();
+        } else {
+            self.pieces.insert(i, Piece { add: true, start, len: text.len() });
+        }
+    }
+
+    pub fn remove(&mut self, start: Pos, end: Pos) -> String {
+        let text = self.get_range(start, end);
+        if text.is_empty() { return text; }
+        let offset = self.offset(start);
+        let first = self.split(offset);
+        let last = self.split(offset + text.len());
+        self.pieces.drain(first..last);
+        self.lines -= text.matches('\n').count();
+        text
+    }
+
+    pub fn text(&self) -> String {
+        self.slices_from(0).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_and_ranges() {
+        let table = PieceTable::new("one\ntwo é\nthree".to_string());
+        assert_eq!(table.num_lines(), 3);
+        assert_eq!(table.line(1).unwrap(), "two é");
+        assert_eq!(table.line(3), None);
+        assert_eq!(table.get_range((0, 2), (1, 5)), "e\ntwo é");
+        // columns past the end of a line stop at it
+        assert_eq!(table.get_range((1, 3), (1, 100)), " é");
+    }
+
+    #[test]
+    fn edits_across_pieces() {
+        let mut table = PieceTable::new("one\ntwo\nthree".to_string());
+        table.insert((1, 3), "!");
+        table.insert((1, 4), "!\nnew");
+        table.insert((0, 0), "é");
+        assert_eq!(table.text(), "éone\ntwo!!\nnew\nthree");
+        assert_eq!(table.num_lines(), 4);
+        assert_eq!(table.line(2).unwrap(), "new");
+
+        assert_eq!(table.remove((0, 3), (2, 1)), "e\ntwo!!\nn");
+        assert_eq!(table.text(), "éonew\nthree");
+        assert_eq!(table.num_lines(), 2);
+        assert_eq!(table.remove((0, 0), (5, 0)), "éonew\nthree");
+        assert_eq!(table.text(), "");
+        assert_eq!(table.num_lines(), 1);
+    }
+}
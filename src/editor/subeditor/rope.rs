@@ -0,0 +1,341 @@
+use super::history::Pos;
+
+static MAX_LEAF: usize = 1024; // bytes of text in a leaf before it is split
+static MAX_SKEW: usize = 2; // how much deeper one side of a node can get before it is rebuilt
+
+// Bytes, chars and newlines in some text
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Len {
+    pub bytes: usize,
+    pub chars: usize,
+    pub lines: usize // newlines, so one less than the lines it spans
+}
+
+impl Len {
+    fn of(text: &str) -> Len {
+        Len { bytes: text.len(), chars: text.chars().count(), lines: text.bytes().filter(|&byte| byte == b'\n').count() }
+    }
+
+    fn add(self, other: Len) -> Len {
+        Len { bytes: self.bytes + other.bytes, chars: self.chars + other.chars, lines: self.lines + other.lines }
+    }
+}
+
+// What an offset into the text is counted in
+#[derive(Clone, Copy)]
+enum Unit {
+    Byte,
+    Char,
+    Line // the offset is a number of newlines, ie. the start of that line
+}
+
+impl Unit {
+    fn of(self, len: Len) -> usize {
+        match self {
+            Unit::Byte => len.bytes,
+            Unit::Char => len.chars,
+            Unit::Line => len.lines
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Kind {
+    Leaf(String),
+    Branch(Box<Node>, Box<Node>)
+}
+
+#[derive(Debug)]
+struct Node {
+    len: Len, // of all the text under it
+    depth: usize,
+    kind: Kind
+}
+
+// Splits text into leaf sized pieces, on char boundaries
+fn chunks(text: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while rest.len() > MAX_LEAF {
+        let mut end = MAX_LEAF;
+        while !rest.is_char_boundary(end) { end -= 1; }
+        chunks.push(rest[..end].to_string());
+        rest = &rest[end..];
+    }
+    chunks.push(rest.to_string());
+    chunks
+}
+
+impl Node {
+
+    fn leaf(text: String) -> Node {
+        Node { len: Len::of(&text), depth: 0, kind: Kind::Leaf(text) }
+    }
+
+    fn branch(left: Node, right: Node) -> Node {
+        Node {
+            len: left.len.add(right.len),
+            depth: 1 + left.depth.max(right.depth),
+            kind: Kind::Branch(Box::new(left), Box::new(right))
+        }
+    }
+
+    // A balanced tree of the leaves
+    fn build(leaves: &mut Vec<String>) -> Node {
+        if leaves.len() <= 1 { return Node::leaf(leaves.pop().unwrap_or_default()); }
+        let mut right = leaves.split_off(leaves.len() / 2);
+        let left = Node::build(leaves);
+        Node::branch(left, Node::build(&mut right))
+    }
+
+    fn from_text(text: &str) -> Node {
+        Node::build(&mut chunks(text))
+    }
+
+    fn collect(&self, text: &mut String) {
+        match &self.kind {
+            Kind::Leaf(leaf) => text.push_str(leaf),
+            Kind::Branch(left, right) => {
+                left.collect(text);
+                right.collect(text);
+            }
+        }
+    }
+
+    // Puts the lengths back together after a child changed, rebuilding the node if one side
+    // got too much deeper than the other
+    fn update(&mut self) {
+        if let Kind::Branch(left, right) = &mut self.kind {
+            // a side that was emptied is dropped
+            if left.len.bytes == 0 || right.len.bytes == 0 {
+                let kept = if left.len.bytes == 0 { right } else { left };
+                let kept = std::mem::replace(kept.as_mut(), Node::leaf(String::new()));
+                *self = kept;
+                return;
+            }
+            self.len = left.len.add(right.len);
+            self.depth = 1 + left.depth.max(right.depth);
+            if left.depth.abs_diff(right.depth) > MAX_SKEW {
+                let mut text = String::with_capacity(self.len.bytes);
+                self.collect(&mut text);
+                *self = Node::from_text(&text);
+            }
+        }
+    }
+
+    // Length of the text before offset `n` counted in `unit`
+    fn prefix(&self, unit: Unit, n: usize) -> Len {
+        match &self.kind {
+            Kind::Branch(left, right) => {
+                // the start of a line is after its newline, which can be the last char on the left
+                let on_left = match unit {
+                    Unit::Line => n <= left.len.lines,
+                    _ => n < unit.of(left.len)
+                };
+                if on_left { left.prefix(unit, n) } else { left.len.add(right.prefix(unit, n - unit.of(left.len))) }
+            }
+            Kind::Leaf(text) => {
+                let end = match unit {
+                    Unit::Byte => (0..=n.min(text.len())).rev().find(|&i| text.is_char_boundary(i)).unwrap_or(0),
+                    Unit::Char => text.char_indices().nth(n).map_or(text.len(), |(i, _)| i),
+                    Unit::Line if n == 0 => 0,
+                    Unit::Line => text.match_indices('\n').nth(n - 1).map_or(text.len(), |(i, _)| i + 1)
+                };
+                Len::of(&text[..end])
+            }
+        }
+    }
+
+    // Appends the bytes from `start` to `end` of the text
+    fn slice(&self, start: usize, end: usize, text: &mut String) {
+        if start >= end { return; }
+        match &self.kind {
+            Kind::Branch(left, right) => {
+                let mid = left.len.bytes;
+                if start < mid { left.slice(start, end.min(mid), text); }
+                if end > mid { right.slice(start.saturating_sub(mid), end - mid, text); }
+            }
+            Kind::Leaf(leaf) => text.push_str(&leaf[start..end.min(leaf.len())])
+        }
+    }
+
+    fn insert(&mut self, at: usize, text: &str) {
+        match &mut self.kind {
+            Kind::Branch(left, right) => {
+                let mid = left.len.chars;
+                if at <= mid { left.insert(at, text); } else { right.insert(at - mid, text); }
+                self.update();
+            }
+            Kind::Leaf(leaf) => {
+                let byte = leaf.char_indices().nth(at).map_or(leaf.len(), |(i, _)| i);
+                leaf.insert_str(byte, text);
+                *self = if leaf.len() > MAX_LEAF { Node::from_text(leaf) } else { Node::leaf(std::mem::take(leaf)) };
+            }
+        }
+    }
+
+    // Removes the chars from `start` to `end`
+    fn remove(&mut self, start: usize, end: usize) {
+        if start >= end { return; }
+        match &mut self.kind {
+            Kind::Branch(left, right) => {
+                let mid = left.len.chars;
+                if start < mid { left.remove(start, end.min(mid)); }
+                if end > mid { right.remove(start.saturating_sub(mid), end - mid); }
+                self.update();
+            }
+            Kind::Leaf(leaf) => {
+                let byte = |n: usize| leaf.char_indices().nth(n).map_or(leaf.len(), |(i, _)| i);
+                let (from, to) = (byte(start), byte(end));
+                leaf.replace_range(from..to, "");
+                self.len = Len::of(leaf);
+            }
+        }
+    }
+}
+
+// Text as a balanced tree of small strings, each node knowing how many bytes, chars and
+// newlines are under it. Getting to a line or converting between byte, char and line offsets
+// goes down the tree, in O(log n) of the size of the text.
+#[derive(Debug)]
+pub struct Rope {
+    root: Node
+}
+
+impl Rope {
+
+    pub fn new(text: &str) -> Rope {
+        Rope { root: Node::from_text(text) }
+    }
+
+    pub fn len(&self) -> Len {
+        self.root.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.len.bytes == 0
+    }
+
+    pub fn num_lines(&self) -> usize {
+        self.root.len.lines + 1
+    }
+
+    pub fn byte_to_char(&self, byte: usize) -> usize {
+        self.root.prefix(Unit::Byte, byte).chars
+    }
+
+    pub fn byte_to_line(&self, byte: usize) -> usize {
+        self.root.prefix(Unit::Byte, byte).lines
+    }
+
+    pub fn char_to_byte(&self, ch: usize) -> usize {
+        self.root.prefix(Unit::Char, ch).bytes
+    }
+
+    pub fn char_to_line(&self, ch: usize) -> usize {
+        self.root.prefix(Unit::Char, ch).lines
+    }
+
+    // Where line n starts, the end of the text past the last line
+    pub fn line_to_byte(&self, line: usize) -> usize {
+        self.root.prefix(Unit::Line, line).bytes
+    }
+
+    pub fn line_to_char(&self, line: usize) -> usize {
+        self.root.prefix(Unit::Line, line).chars
+    }
+
+    // Char offset of a (line, column) position, clamped to its line and to the text
+    pub fn pos_to_char(&self, (line, col): Pos) -> usize {
+        if line >= self.num_lines() { return self.root.len.chars; }
+        let start = self.line_to_char(line);
+        let end = if line + 1 < self.num_lines() { self.line_to_char(line + 1) - 1 } else { self.root.len.chars };
+        start + col.min(end - start)
+    }
+
+    // The text between two byte offsets
+    pub fn slice(&self, start: usize, end: usize) -> String {
+        let mut text = String::with_capacity(end.saturating_sub(start));
+        self.root.slice(start, end, &mut text);
+        text
+    }
+
+    pub fn line(&self, n: usize) -> Option<String> {
+        if n >= self.num_lines() { return None; }
+        let end = if n + 1 < self.num_lines() { self.line_to_byte(n + 1) - 1 } else { self.root.len.bytes };
+        Some(self.slice(self.line_to_byte(n), end))
+    }
+
+    pub fn insert(&mut self, ch: usize, text: &str) {
+        if text.is_empty() { return; }
+        self.root.insert(ch.min(self.root.len.chars), text);
+    }
+
+    // Removes the chars from `start` to `end`, returns them
+    pub fn remove(&mut self, start: usize, end: usize) -> String {
+        let text = self.slice(self.char_to_byte(start), self.char_to_byte(end));
+        self.root.remove(start, end);
+        text
+    }
+
+    pub fn text(&self) -> String {
+        self.slice(0, self.root.len.bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // long enough to need many leaves, with multi-byte chars falling on leaf boundaries
+    fn text() -> String {
+        (0..500).map(|n| format!("line {} é€\n", n)).collect()
+    }
+
+    #[test]
+    fn offsets_convert_both_ways() {
+        let text = text();
+        let rope = Rope::new(&text);
+        assert_eq!(rope.len(), Len::of(&text));
+        assert_eq!(rope.num_lines(), 501);
+        for (ch, (byte, _)) in text.char_indices().enumerate().step_by(7) {
+            assert_eq!(rope.char_to_byte(ch), byte);
+            assert_eq!(rope.byte_to_char(byte), ch);
+            assert_eq!(rope.char_to_line(ch), text[..byte].matches('\n').count());
+        }
+        let start = text.find("line 42 ").unwrap();
+        assert_eq!(rope.line_to_byte(42), start);
+        assert_eq!(rope.byte_to_line(start), 42);
+        assert_eq!(rope.line_to_char(42), text[..start].chars().count());
+        assert_eq!(rope.line(42).unwrap(), "line 42 é€");
+        assert_eq!(rope.line(500).unwrap(), "");
+        assert_eq!(rope.line(501), None);
+        // past the end of a line or of the text is clamped
+        assert_eq!(rope.pos_to_char((1, 100)), rope.line_to_char(2) - 1);
+        assert_eq!(rope.pos_to_char((600, 0)), rope.len().chars);
+    }
+
+    #[test]
+    fn edits_keep_the_counts() {
+        let mut text = text();
+        let mut rope = Rope::new(&text);
+        for n in 0..200 {
+            let ch = (n * 37) % rope.len().chars;
+            let byte = text.char_indices().nth(ch).unwrap().0;
+            rope.insert(ch, "ñ\nx");
+            text.insert_str(byte, "ñ\nx");
+            if n % 3 == 0 {
+                let end = text[byte..].char_indices().nth(5).unwrap().0 + byte;
+                assert_eq!(rope.remove(ch, ch + 5), &text[byte..end]);
+                text.replace_range(byte..end, "");
+            }
+        }
+        assert_eq!(rope.text(), text);
+        assert_eq!(rope.len(), Len::of(&text));
+        assert_eq!(rope.num_lines(), text.matches('\n').count() + 1);
+
+        rope.remove(0, rope.len().chars);
+        assert!(rope.is_empty());
+        assert_eq!(rope.num_lines(), 1);
+    }
+}
@@ -0,0 +1,135 @@
+use regex::{NoExpand, Regex, RegexBuilder};
+
+// What to search for and how
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub pattern: String,
+    pub regex: bool,
+    pub ignore_case: bool,
+    pub whole_word: bool
+}
+
+// A compiled query, literal patterns are escaped and searched with a regex too
+#[derive(Debug)]
+pub struct Matcher {
+    re: Regex,
+    expand: bool // if $1 style references in replacements are expanded
+}
+
+fn byte_to_col(line: &str, byte: usize) -> usize {
+    line[..byte].chars().count()
+}
+
+fn col_to_byte(line: &str, col: usize) -> usize {
+    line.char_indices().nth(col).map_or(line.len(), |(byte, _)| byte)
+}
+
+impl Query {
+
+    // The options that are on, eg. " [regex, word]"
+    pub fn flags(&self) -> String {
+        let mut flags = Vec::new();
+        if self.regex { flags.push("regex"); }
+        if self.ignore_case { flags.push("ignore case"); }
+        if self.whole_word { flags.push("word"); }
+        if flags.is_empty() { String::new() } else { format!(" [{}]", flags.join(", ")) }
+    }
+
+    // None if the pattern is empty or not a valid regex
+    pub fn compile(&self) -> Option<Matcher> {
+        if self.pattern.is_empty() { return None; }
+        let mut pattern = if self.regex { self.pattern.clone() } else { regex::escape(&self.pattern) };
+        if self.whole_word { pattern = format!(r"\b(?:{})\b", pattern); }
+
+        let re = RegexBuilder::new(&pattern).case_insensitive(self.ignore_case).build().ok()?;
+        Some(Matcher { re, expand: self.regex })
+    }
+}
+
+impl Matcher {
+
+    // Column ranges (end excluded) of the non overlapping matches in `line`
+    pub fn find_all(&self, line: &str) -> Vec<(usize, usize)> {
+        // the matches are in byte offsets, turn them into columns as we go
+        let mut matches = Vec::new();
+        let (mut col, mut byte) = (0, 0);
+        for m in self.re.find_iter(line) {
+            col += byte_to_col(&line[byte..], m.start() - byte);
+            let end = col + m.as_str().chars().count();
+            matches.push((col, end));
+            col = end;
+            byte = m.end();
+        }
+        matches
+    }
+
+    // The line with every match replaced and the number of matches, None if there were none
+    pub fn replace_all(&self, line: &str, with: &str) -> Option<(String, usize)> {
+        let count = self.re.find_iter(line).count();
+        if count == 0 { return None; }
+        let replaced = match self.expand {
+            true => self.re.replace_all(line, with),
+            false => self.re.replace_all(line, NoExpand(with))
+        };
+        Some((replaced.to_string(), count))
+    }
+
+    // The end column of the match starting at column `col` and the text to replace it with
+    pub fn replacement(&self, line: &str, col: usize, with: &str) -> Option<(usize, String)> {
+        let byte = col_to_byte(line, col);
+        let caps = self.re.captures_at(line, byte)?;
+        let m = caps.get(0)?;
+        if m.start() != byte { return None; }
+
+        let mut text = String::new();
+        match self.expand {
+            true => caps.expand(with, &mut text),
+            false => text.push_str(with)
+        }
+        Some((col + m.as_str().chars().count(), text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(pattern: &str, regex: bool, ignore_case: bool, whole_word: bool) -> Matcher {
+        Query { pattern: pattern.to_string(), regex, ignore_case, whole_word }.compile().unwrap()
+    }
+
+    #[test]
+    fn finds_columns_not_bytes() {
+        assert_eq!(matcher("é", false, false, false).find_all("éaé"), vec![(0, 1), (2, 3)]);
+        assert!(Query::default().compile().is_none());
+        assert!(Query { pattern: "(".to_string(), regex: true, ..Default::default() }.compile().is_none());
+    }
+
+    #[test]
+    fn replace_all_expands_groups_only_for_regexes() {
+        let swap = matcher(r"(\w+)=(\w+)", true, false, false);
+        assert_eq!(swap.replace_all("a=1, b=2", "$2=$1"), Some(("1=a, 2=b".to_string(), 2)));
+        // a literal pattern is escaped, and so is its replacement
+        let literal = matcher("a.b", false, false, false);
+        assert_eq!(literal.replace_all("a.b axb", "$1"), Some(("$1 axb".to_string(), 1)));
+        assert_eq!(literal.replace_all("nothing", "x"), None);
+    }
+
+    #[test]
+    fn replace_all_with_options() {
+        assert_eq!(matcher("cat", false, true, false).replace_all("Cat CAT cat", "dog"), Some(("dog dog dog".to_string(), 3)));
+        assert_eq!(matcher("cat", false, false, true).replace_all("cat concat cats cat", "dog"), Some(("dog concat cats dog".to_string(), 2)));
+    }
+
+    #[test]
+    fn replacement_of_one_match() {
+        let swap = matcher(r"(\w)(\w)", true, false, false);
+        assert_eq!(swap.replacement("éx ab", 3, "$2$1"), Some((5, "ba".to_string())));
+        // columns count chars, and there has to be a match right at the column
+        assert_eq!(swap.replacement("éx ab", 0, "$2$1"), Some((2, "xé".to_string())));
+        assert_eq!(swap.replacement("éx ab", 2, "$2$1"), None);
+        let word = matcher("ab", false, true, true);
+        assert_eq!(word.replacement("AB abc", 0, "$0"), Some((2, "$0".to_string())));
+        assert_eq!(word.replacement("AB abc", 3, "x"), None);
+    }
+}
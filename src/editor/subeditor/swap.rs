@@ -0,0 +1,72 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+
+// A swap file holds the unsaved contents of a buffer, so that they survive a crash
+static MAGIC: &str = "med-swap 1";
+
+pub struct Swap {
+    pub pid: u32, // the med that wrote it
+    pub lines: Vec<String>
+}
+
+// The hidden file next to `path` that its unsaved changes are kept in
+pub fn swap_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_string());
+    path.with_file_name(format!(".{}.med-swap", name))
+}
+
+// Written before the lines of the buffer
+pub fn write_header(out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "{}", MAGIC)?;
+    writeln!(out, "pid {}", process::id())
+}
+
+pub fn read(path: &Path) -> io::Result<Swap> {
+    let text = std::fs::read_to_string(path)?;
+    // not lines(), which would also take a \r at the end of a line away
+    let mut lines = text.strip_suffix('\n').unwrap_or(&text).split('\n');
+
+    if lines.next() != Some(MAGIC) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a med swap file"));
+    }
+    let pid = lines.next().and_then(|line| line.strip_prefix("pid ")).and_then(|pid| pid.parse().ok())
+              .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad swap file"))?;
+
+    Ok(Swap { pid, lines: lines.map(|line| line.to_string()).collect() })
+}
+
+// If the med that wrote a swap file is still running, in which case its changes are not lost but
+// still being made
+#[cfg(unix)]
+pub fn running(pid: u32) -> bool {
+    if pid == process::id() { return false; }
+    // a process of another user cannot be signalled, but can still be seen in /proc
+    let proc = Path::new("/proc");
+    if proc.join("self").exists() { return proc.join(pid.to_string()).exists(); }
+    Command::new("kill").args(["-0", &pid.to_string()]).stderr(Stdio::null()).status().is_ok_and(|status| status.success())
+}
+
+#[cfg(not(unix))]
+pub fn running(pid: u32) -> bool {
+    if pid == process::id() { return false; }
+    Command::new("tasklist").args(["/FI", &format!("PID eq {}", pid), "/NH"]).stderr(Stdio::null()).output()
+        .is_ok_and(|out| String::from_utf8_lossy(&out.stdout).split_whitespace().any(|word| word == pid.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn running_processes() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        assert!(running(child.id()));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(!running(child.id()));
+        // the swap file of this very med is not another one editing the file
+        assert!(!running(process::id()));
+    }
+}
@@ -1,24 +1,84 @@
-mod editor;
-use std::io::{self,stdout};
+use med::{cli, editor};
+use std::io::{self,stdin,stdout};
+use std::panic::{self, AssertUnwindSafe};
 
 fn main() -> Result<(), io::Error>{
 
-    let fname = "/home/manan/Projects/rust-editor/files/test1.txt";
-
-    let mut ed = editor::Editor {
-        term: &mut stdout(),
-        subed: editor::subeditor::SubEditor::open(fname).unwrap(),
-        fname: fname,
+    let opts = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli::Cli::Edit(opts)) => opts,
+        Ok(cli::Cli::Help) => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Ok(cli::Cli::Version) => {
+            println!("med {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("med: {}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
     };
 
-    if let Err(e) = ed.start() {
-        println!("Med stopped unexpectedly :( ({})", e);
-        ed.exit()?;
-        std::process::exit(1);
+    // the terminal has to leave raw mode and the alternate screen for the message to be seen
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        editor::restore_terminal();
+        default_hook(info);
+    }));
+
+    let backing = if opts.piece_table { editor::subeditor::Backing::PieceTable } else { editor::subeditor::Backing::Rope };
+    for target in opts.files.iter() {
+        let mut path = target.path.clone();
+        let mut line = target.line;
+        // picking an entry of a directory listing opens it in place of the listing
+        loop {
+            let mut out = stdout();
+            let ed = if path == cli::STDIN {
+                editor::subeditor::SubEditor::from_reader(stdin(), backing)
+                    .map(|subed| editor::Editor::new(&mut out, subed, &path, opts.readonly))
+            } else {
+                editor::Editor::open(&mut out, &path, opts.readonly, backing)
+            };
+            let mut ed = match ed {
+                Ok(ed) => ed,
+                Err(e) => {
+                    eprintln!("med: {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            };
+
+            ed.backup = opts.backup;
+            ed.indent = editor::indent::Indent::new(opts.tab_width, opts.soft_tabs, &path);
+            if let Some(line) = line.take() {
+                ed.subed.move_to_line(line - 1);
+            }
+
+            let guard = editor::TerminalGuard;
+            let res = panic::catch_unwind(AssertUnwindSafe(|| ed.start()));
+            drop(guard);
+
+            match res {
+                Ok(Ok(())) => match ed.next.take() {
+                    Some(next) => {
+                        path = next;
+                        continue;
+                    }
+                    None => break
+                },
+                Ok(Err(e)) => println!("Med stopped unexpectedly :( ({})", e),
+                // the panic hook already told what happened
+                Err(_) => println!("Med crashed :(")
+            }
+            // the buffer may be broken after a panic, so dumping it can panic too
+            match panic::catch_unwind(AssertUnwindSafe(|| ed.dump())) {
+                Ok(Ok(path)) => println!("The buffer was saved to {}", path.display()),
+                Ok(Err(e)) => println!("The buffer could not be saved ({})", e),
+                Err(_) => println!("The buffer could not be saved")
+            }
+            std::process::exit(1);
+        }
     }
-    ed.exit()?;
-    
+
     Ok(())
 }
-
-